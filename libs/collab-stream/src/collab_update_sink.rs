@@ -1,12 +1,15 @@
 use crate::error::StreamError;
-use crate::model::{AwarenessStreamUpdate, CollabStreamUpdate, MessageId};
+use crate::model::{
+  AppendOptions, AwarenessStreamUpdate, CollabStreamUpdate, MessageId, ToFieldsArray,
+};
 use redis::aio::ConnectionManager;
-use redis::cmd;
+use redis::{cmd, FromRedisValue, Value};
 use tokio::sync::Mutex;
 
 pub struct CollabUpdateSink {
   conn: Mutex<ConnectionManager>,
   stream_key: String,
+  checksum: bool,
 }
 
 impl CollabUpdateSink {
@@ -14,24 +17,59 @@ impl CollabUpdateSink {
     CollabUpdateSink {
       conn: conn.into(),
       stream_key,
+      checksum: false,
     }
   }
 
+  /// Appends a `checksum` field to every sent update, verified by readers. Only enable it once
+  /// all readers of the stream understand the field.
+  pub fn with_checksum(mut self) -> Self {
+    self.checksum = true;
+    self
+  }
+
   pub async fn send(&self, msg: &CollabStreamUpdate) -> Result<MessageId, StreamError> {
     let mut lock = self.conn.lock().await;
-    let msg_id: MessageId = cmd("XADD")
-      .arg(&self.stream_key)
-      .arg("*")
-      .arg("flags")
-      .arg(msg.flags)
-      .arg("sender")
-      .arg(msg.sender.to_string())
-      .arg("data")
-      .arg(&*msg.data)
+    let msg_id: MessageId = self
+      .append_options()
+      .cmd(&self.stream_key, msg)
       .query_async(&mut *lock)
       .await?;
     Ok(msg_id)
   }
+
+  /// Same as [CollabUpdateSink::send], but uses `XADD NOMKSTREAM`, so the stream is not created
+  /// if it doesn't exist. In that case nothing is appended and `None` is returned.
+  pub async fn append_nomkstream(
+    &self,
+    msg: &CollabStreamUpdate,
+  ) -> Result<Option<MessageId>, StreamError> {
+    let mut lock = self.conn.lock().await;
+    let reply: Value = self
+      .append_options()
+      .with_nomkstream()
+      .cmd(&self.stream_key, msg)
+      .query_async(&mut *lock)
+      .await?;
+    parse_append_reply(&reply)
+  }
+
+  fn append_options(&self) -> AppendOptions {
+    let options = AppendOptions::new();
+    if self.checksum {
+      options.with_checksum()
+    } else {
+      options
+    }
+  }
+}
+
+/// Parses `XADD` reply, which is nil when `NOMKSTREAM` was used and the stream didn't exist.
+fn parse_append_reply(reply: &Value) -> Result<Option<MessageId>, StreamError> {
+  match reply {
+    Value::Nil => Ok(None),
+    other => Ok(Some(MessageId::from_redis_value(other)?)),
+  }
 }
 
 pub struct AwarenessUpdateSink {
@@ -55,12 +93,35 @@ impl AwarenessUpdateSink {
       .arg("~")
       .arg(100) // we cap awareness stream to at most 20 awareness updates
       .arg("*")
-      .arg("sender")
-      .arg(msg.sender.to_string())
-      .arg("data")
-      .arg(&*msg.data)
+      .arg(&msg.to_fields_array())
       .query_async(&mut *lock)
       .await?;
     Ok(msg_id)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use crate::collab_update_sink::parse_append_reply;
+  use crate::model::MessageId;
+  use redis::Value;
+
+  #[test]
+  fn nomkstream_nil_reply_is_none() {
+    assert_eq!(parse_append_reply(&Value::Nil).unwrap(), None);
+  }
+
+  #[test]
+  fn append_reply_with_id_is_some() {
+    let reply = Value::Data(b"1700000000000-3".to_vec());
+    assert_eq!(
+      parse_append_reply(&reply).unwrap(),
+      Some(MessageId::new(1700000000000, 3))
+    );
+  }
+
+  #[test]
+  fn append_reply_of_unexpected_type_fails() {
+    assert!(parse_append_reply(&Value::Int(1)).is_err());
+  }
+}
@@ -1,9 +1,17 @@
+#[cfg(feature = "redis")]
 use redis::RedisError;
 
 #[derive(thiserror::Error, Debug)]
 pub enum StreamError {
+  #[cfg(feature = "redis")]
   #[error(transparent)]
-  RedisError(#[from] RedisError),
+  RedisError(RedisError),
+
+  #[error("Wrong type: {0}")]
+  WrongType(String),
+
+  #[error("Stream group not exist: {0}")]
+  NoGroup(String),
 
   #[error("Stream already exist: {0}")]
   StreamAlreadyExist(String),
@@ -20,6 +28,9 @@ pub enum StreamError {
   #[error("Invalid format")]
   InvalidFormat,
 
+  #[error("payload is truncated")]
+  Truncated,
+
   #[error(transparent)]
   ParseIntError(#[from] std::num::ParseIntError),
 
@@ -32,23 +43,254 @@ pub enum StreamError {
   #[error(transparent)]
   BinCodeSerde(#[from] bincode::Error),
 
+  #[cfg(feature = "redis")]
   #[error("failed to decode update: {0}")]
   UpdateError(#[from] collab::preclude::encoding::read::Error),
 
   #[error("I/O error: {0}")]
   IO(#[from] std::io::Error),
 
+  #[error("checksum mismatch: expected {expected}, got {actual}")]
+  ChecksumMismatch { expected: u32, actual: u32 },
+
+  #[error("unsupported: {0}")]
+  Unsupported(String),
+
+  #[error("decompressed payload exceeds the limit of {0} bytes")]
+  TooLarge(usize),
+
   #[error("Internal error: {0}")]
   Internal(anyhow::Error),
 }
 
+/// Stable, exhaustive classification of [StreamError], ie. for mapping errors into HTTP status
+/// codes without depending on the inner error types.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StreamErrorCode {
+  Redis,
+  WrongType,
+  NoGroup,
+  StreamAlreadyExist,
+  StreamNotExist,
+  GroupAlreadyExists,
+  UnexpectedValue,
+  InvalidFormat,
+  /// Payload ended prematurely, ie. it was cut off in transit.
+  Truncated,
+  /// Payload couldn't be parsed or deserialized.
+  Decode,
+  Io,
+  ChecksumMismatch,
+  Unsupported,
+  TooLarge,
+  Internal,
+}
+
 impl StreamError {
+  pub fn code(&self) -> StreamErrorCode {
+    match self {
+      #[cfg(feature = "redis")]
+      StreamError::RedisError(_) => StreamErrorCode::Redis,
+      StreamError::WrongType(_) => StreamErrorCode::WrongType,
+      StreamError::NoGroup(_) => StreamErrorCode::NoGroup,
+      StreamError::StreamAlreadyExist(_) => StreamErrorCode::StreamAlreadyExist,
+      StreamError::StreamNotExist(_) => StreamErrorCode::StreamNotExist,
+      StreamError::GroupAlreadyExists(_) => StreamErrorCode::GroupAlreadyExists,
+      StreamError::UnexpectedValue(_) => StreamErrorCode::UnexpectedValue,
+      StreamError::InvalidFormat => StreamErrorCode::InvalidFormat,
+      StreamError::Truncated => StreamErrorCode::Truncated,
+      StreamError::Utf8Error(_)
+      | StreamError::ParseIntError(_)
+      | StreamError::SerdeJsonError(_)
+      | StreamError::BinCodeSerde(_) => StreamErrorCode::Decode,
+      #[cfg(feature = "redis")]
+      StreamError::UpdateError(_) => StreamErrorCode::Decode,
+      StreamError::IO(_) => StreamErrorCode::Io,
+      StreamError::ChecksumMismatch { .. } => StreamErrorCode::ChecksumMismatch,
+      StreamError::Unsupported(_) => StreamErrorCode::Unsupported,
+      StreamError::TooLarge(_) => StreamErrorCode::TooLarge,
+      StreamError::Internal(_) => StreamErrorCode::Internal,
+    }
+  }
+
   pub fn is_stream_not_exist(&self) -> bool {
     matches!(self, StreamError::StreamNotExist(_))
   }
+
+  pub fn is_no_group(&self) -> bool {
+    matches!(self, StreamError::NoGroup(_))
+  }
+}
+
+/// Redis error replies that callers commonly need to branch on are mapped into dedicated
+/// variants: `WRONGTYPE` into [StreamError::WrongType], `NOGROUP` into [StreamError::NoGroup] and
+/// `BUSYGROUP` into [StreamError::GroupAlreadyExists].
+#[cfg(feature = "redis")]
+impl From<RedisError> for StreamError {
+  fn from(err: RedisError) -> Self {
+    match err.code() {
+      Some("WRONGTYPE") => StreamError::WrongType(err.to_string()),
+      Some("NOGROUP") => StreamError::NoGroup(err.to_string()),
+      Some("BUSYGROUP") => StreamError::GroupAlreadyExists(err.to_string()),
+      _ => StreamError::RedisError(err),
+    }
+  }
+}
+
+impl From<StreamError> for std::io::Error {
+  fn from(err: StreamError) -> Self {
+    use std::io::ErrorKind;
+    match err {
+      StreamError::IO(err) => err,
+      StreamError::UnexpectedValue(_)
+      | StreamError::Utf8Error(_)
+      | StreamError::InvalidFormat
+      | StreamError::Truncated
+      | StreamError::ParseIntError(_)
+      | StreamError::SerdeJsonError(_)
+      | StreamError::BinCodeSerde(_)
+      | StreamError::TooLarge(_)
+      | StreamError::ChecksumMismatch { .. }
+      | StreamError::Unsupported(_) => std::io::Error::new(ErrorKind::InvalidData, err),
+      #[cfg(feature = "redis")]
+      StreamError::UpdateError(_) => std::io::Error::new(ErrorKind::InvalidData, err),
+      #[cfg(feature = "redis")]
+      StreamError::RedisError(_) => std::io::Error::new(ErrorKind::Other, err),
+      StreamError::StreamAlreadyExist(_)
+      | StreamError::StreamNotExist(_)
+      | StreamError::WrongType(_)
+      | StreamError::NoGroup(_)
+      | StreamError::GroupAlreadyExists(_)
+      | StreamError::Internal(_) => std::io::Error::new(ErrorKind::Other, err),
+    }
+  }
 }
 
+#[cfg(feature = "redis")]
 pub fn internal<T: ToString>(msg: T) -> RedisError {
   let msg = msg.to_string();
   RedisError::from((redis::ErrorKind::TypeError, "", msg))
 }
+
+#[cfg(all(test, feature = "redis"))]
+mod test {
+  use crate::error::{StreamError, StreamErrorCode};
+  use collab::preclude::updates::decoder::Decode;
+  use std::io::ErrorKind;
+
+  #[test]
+  fn stream_error_into_io_error() {
+    let err: std::io::Error = StreamError::InvalidFormat.into();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let err: std::io::Error = StreamError::StreamNotExist("stream".to_string()).into();
+    assert_eq!(err.kind(), ErrorKind::Other);
+
+    let err: std::io::Error = StreamError::IO(ErrorKind::UnexpectedEof.into()).into();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+  }
+
+  fn redis_error_reply(reply: &[u8]) -> redis::RedisError {
+    redis::parse_redis_value(reply).unwrap_err()
+  }
+
+  #[test]
+  fn redis_error_codes_into_stream_error() {
+    let err = StreamError::from(redis_error_reply(
+      b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+    ));
+    assert!(matches!(err, StreamError::WrongType(_)));
+
+    let err = StreamError::from(redis_error_reply(
+      b"-NOGROUP No such key 'stream' or consumer group 'group'\r\n",
+    ));
+    assert!(err.is_no_group());
+
+    let err = StreamError::from(redis_error_reply(
+      b"-BUSYGROUP Consumer Group name already exists\r\n",
+    ));
+    assert!(matches!(err, StreamError::GroupAlreadyExists(_)));
+
+    let err = StreamError::from(redis_error_reply(b"-ERR unknown command\r\n"));
+    assert!(matches!(err, StreamError::RedisError(_)));
+  }
+
+  #[test]
+  fn stream_error_codes() {
+    let cases = vec![
+      (
+        StreamError::from(redis_error_reply(b"-ERR unknown command\r\n")),
+        StreamErrorCode::Redis,
+      ),
+      (
+        StreamError::WrongType("key".to_string()),
+        StreamErrorCode::WrongType,
+      ),
+      (
+        StreamError::NoGroup("group".to_string()),
+        StreamErrorCode::NoGroup,
+      ),
+      (
+        StreamError::StreamAlreadyExist("stream".to_string()),
+        StreamErrorCode::StreamAlreadyExist,
+      ),
+      (
+        StreamError::StreamNotExist("stream".to_string()),
+        StreamErrorCode::StreamNotExist,
+      ),
+      (
+        StreamError::GroupAlreadyExists("group".to_string()),
+        StreamErrorCode::GroupAlreadyExists,
+      ),
+      (
+        StreamError::UnexpectedValue("value".to_string()),
+        StreamErrorCode::UnexpectedValue,
+      ),
+      (StreamError::InvalidFormat, StreamErrorCode::InvalidFormat),
+      (StreamError::Truncated, StreamErrorCode::Truncated),
+      (
+        StreamError::from(String::from_utf8(vec![0xff]).unwrap_err().utf8_error()),
+        StreamErrorCode::Decode,
+      ),
+      (
+        StreamError::from("x".parse::<u8>().unwrap_err()),
+        StreamErrorCode::Decode,
+      ),
+      (
+        StreamError::from(serde_json::from_str::<u8>("x").unwrap_err()),
+        StreamErrorCode::Decode,
+      ),
+      (
+        StreamError::from(bincode::deserialize::<u64>(&[]).unwrap_err()),
+        StreamErrorCode::Decode,
+      ),
+      (
+        StreamError::from(collab::preclude::Update::decode_v1(&[0xff; 16]).unwrap_err()),
+        StreamErrorCode::Decode,
+      ),
+      (
+        StreamError::IO(ErrorKind::UnexpectedEof.into()),
+        StreamErrorCode::Io,
+      ),
+      (
+        StreamError::ChecksumMismatch {
+          expected: 1,
+          actual: 2,
+        },
+        StreamErrorCode::ChecksumMismatch,
+      ),
+      (
+        StreamError::Unsupported("lz4".to_string()),
+        StreamErrorCode::Unsupported,
+      ),
+      (StreamError::TooLarge(1024), StreamErrorCode::TooLarge),
+      (
+        StreamError::Internal(anyhow::anyhow!("internal")),
+        StreamErrorCode::Internal,
+      ),
+    ];
+    for (err, code) in cases {
+      assert_eq!(err.code(), code, "{}", err);
+    }
+  }
+}
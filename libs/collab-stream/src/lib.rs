@@ -1,9 +1,20 @@
+#[cfg(feature = "redis")]
 pub mod client;
+#[cfg(feature = "redis")]
 pub mod collab_update_sink;
 pub mod error;
+#[cfg(feature = "redis")]
 pub mod lease;
+#[cfg(feature = "redis")]
 pub mod metrics;
+#[cfg(feature = "redis")]
 pub mod model;
+pub mod primitives;
+#[cfg(feature = "redis")]
 pub mod pubsub;
+#[cfg(feature = "redis")]
+pub mod stream_consumer;
+#[cfg(feature = "redis")]
 pub mod stream_group;
+#[cfg(feature = "redis")]
 pub mod stream_router;
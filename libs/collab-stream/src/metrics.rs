@@ -1,5 +1,13 @@
+#[cfg(feature = "metrics")]
+use crate::model::{Compression, Encoding, UpdateFlags};
+#[cfg(feature = "metrics")]
+use prometheus_client::encoding::EncodeLabelSet;
 use prometheus_client::metrics::counter::Counter;
+#[cfg(feature = "metrics")]
+use prometheus_client::metrics::family::Family;
 use prometheus_client::registry::Registry;
+#[cfg(feature = "metrics")]
+use std::sync::OnceLock;
 
 #[derive(Default)]
 pub struct CollabStreamMetrics {
@@ -23,6 +31,49 @@ impl CollabStreamMetrics {
       "Incremented each time an existing task is consumed (including recurring tasks).",
       metrics.reads_dequeued.clone(),
     );
+    #[cfg(feature = "metrics")]
+    realtime_registry.register(
+      "update_decodes",
+      "Incremented each time a collab stream update is decoded.",
+      decode_counter().clone(),
+    );
     metrics
   }
 }
+
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DecodeLabel {
+  /// `v1` or `v2`.
+  pub encoding: &'static str,
+  /// `none`, `zstd` or `lz4`.
+  pub compression: &'static str,
+  /// `ok` or `error`.
+  pub outcome: &'static str,
+}
+
+/// Counter of collab stream update decodes. Decoding happens outside of any component owning
+/// [CollabStreamMetrics], so the counter is global. It's registered by
+/// [CollabStreamMetrics::register].
+#[cfg(feature = "metrics")]
+pub fn decode_counter() -> &'static Family<DecodeLabel, Counter> {
+  static DECODES: OnceLock<Family<DecodeLabel, Counter>> = OnceLock::new();
+  DECODES.get_or_init(Family::default)
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_decode(flags: UpdateFlags, success: bool) {
+  let label = DecodeLabel {
+    encoding: match flags.encoding() {
+      Encoding::V1 => "v1",
+      Encoding::V2 => "v2",
+    },
+    compression: match flags.compression() {
+      Compression::None => "none",
+      Compression::Zstd => "zstd",
+      Compression::Lz4 => "lz4",
+    },
+    outcome: if success { "ok" } else { "error" },
+  };
+  decode_counter().get_or_create(&label).inc();
+}
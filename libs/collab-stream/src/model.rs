@@ -1,83 +1,33 @@
 use crate::error::{internal, StreamError};
 use bytes::Bytes;
+use collab::core::awareness::AwarenessUpdate;
 use collab::core::origin::{CollabClient, CollabOrigin};
 use collab::preclude::updates::decoder::Decode;
+use collab::preclude::updates::encoder::Encode;
+use collab::preclude::StateVector;
 use collab_entity::proto::collab::collab_update_event::Update;
 use collab_entity::{proto, CollabType};
 use prost::Message;
 use redis::streams::StreamId;
 use redis::{FromRedisValue, RedisError, RedisResult, RedisWrite, ToRedisArgs, Value};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use std::fmt::{Display, Formatter};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Read;
 use std::ops::Deref;
-use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// The [MessageId] generated by XADD has two parts: a timestamp and a sequence number, separated by
-/// a hyphen (-). The timestamp is based on the server's time when the message is added, and the
-/// sequence number is used to differentiate messages added at the same millisecond.
-///
-///  If multiple messages are added within the same millisecond, Redis increments the sequence number
-/// for each subsequent message
-///
-/// An example message ID might look like this: 1631020452097-0. In this example, 1631020452097 is
-/// the timestamp in milliseconds, and 0 is the sequence number.
-#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq)]
-pub struct MessageId {
-  pub timestamp_ms: u64,
-  pub sequence_number: u16,
-}
-
-impl MessageId {
-  pub fn new(timestamp_ms: u64, sequence_number: u16) -> Self {
-    MessageId {
-      timestamp_ms,
-      sequence_number,
-    }
-  }
-}
-
-impl Display for MessageId {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}-{}", self.timestamp_ms, self.sequence_number)
-  }
-}
-
-impl TryFrom<&[u8]> for MessageId {
-  type Error = StreamError;
-
-  fn try_from(s: &[u8]) -> Result<Self, Self::Error> {
-    let s = std::str::from_utf8(s)?;
-    Self::try_from(s)
-  }
-}
+pub use crate::primitives::{
+  Compression, CursorSnapshot, Encoding, MessageId, MessageIdRange, MessageIdStr, UpdateFlags,
+};
 
-impl TryFrom<&str> for MessageId {
+impl TryFrom<StreamId> for MessageId {
   type Error = StreamError;
 
-  fn try_from(s: &str) -> Result<Self, Self::Error> {
-    let parts: Vec<_> = s.splitn(2, '-').collect();
-
-    if parts.len() != 2 {
-      return Err(StreamError::InvalidFormat);
-    }
-
-    // Directly parse without intermediate assignment.
-    let timestamp_ms = u64::from_str(parts[0])?;
-    let sequence_number = u16::from_str(parts[1])?;
-
-    Ok(MessageId {
-      timestamp_ms,
-      sequence_number,
-    })
-  }
-}
-
-impl TryFrom<String> for MessageId {
-  type Error = StreamError;
-
-  fn try_from(s: String) -> Result<Self, Self::Error> {
-    Self::try_from(s.as_str())
+  fn try_from(value: StreamId) -> Result<Self, Self::Error> {
+    MessageId::try_from(value.id.as_str())
   }
 }
 
@@ -91,11 +41,23 @@ impl FromRedisValue for MessageId {
           format!("{:?}", stream_key),
         ))
       }),
-      _ => Err(internal("expecting Value::Data")),
+      _ => Err(internal(format!(
+        "expecting Value::Data, got {}",
+        describe_value(v)
+      ))),
     }
   }
 }
 
+impl ToRedisArgs for MessageId {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    self.to_string().write_redis_args(out)
+  }
+}
+
 #[derive(Debug)]
 pub struct StreamMessageByStreamKey(pub BTreeMap<String, Vec<StreamMessage>>);
 
@@ -119,10 +81,11 @@ impl FromRedisValue for StreamMessageByStreamKey {
       }
 
       let stream_key = RedisString::from_redis_value(&key_values[0])?.0;
-      let values = bulk_from_redis_value(&key_values[1])?.iter();
+      let values = bulk_from_redis_value(&key_values[1])?;
+      let messages = map.entry(stream_key).or_default();
+      messages.reserve_exact(values.len());
       for value in values {
-        let value = StreamMessage::from_redis_value(value)?;
-        map.entry(stream_key.clone()).or_default().push(value);
+        messages.push(StreamMessage::from_redis_value(value)?);
       }
     }
 
@@ -130,12 +93,125 @@ impl FromRedisValue for StreamMessageByStreamKey {
   }
 }
 
+/// Reply of a blocking `XREAD`/`XREADGROUP`. Unlike [StreamMessageByStreamKey], which parses a nil
+/// reply as an empty map, it tells apart a `BLOCK` timeout (nil reply) from a reply with no
+/// messages.
+#[derive(Debug)]
+pub enum ReadOutcome {
+  /// No message arrived before the `BLOCK` timeout.
+  TimedOut,
+  Messages(StreamMessageByStreamKey),
+}
+
+impl ReadOutcome {
+  pub fn is_timed_out(&self) -> bool {
+    matches!(self, ReadOutcome::TimedOut)
+  }
+
+  /// Returns read messages, treating timeout as no messages.
+  pub fn into_messages(self) -> StreamMessageByStreamKey {
+    match self {
+      ReadOutcome::TimedOut => StreamMessageByStreamKey(BTreeMap::new()),
+      ReadOutcome::Messages(messages) => messages,
+    }
+  }
+}
+
+impl FromRedisValue for ReadOutcome {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    match v {
+      Value::Nil => Ok(ReadOutcome::TimedOut),
+      other => Ok(ReadOutcome::Messages(
+        StreamMessageByStreamKey::from_redis_value(other)?,
+      )),
+    }
+  }
+}
+
+impl StreamMessageByStreamKey {
+  /// Drops all messages sent by `origin`, ie. so that updates are not echoed back to the client
+  /// that produced them. Messages without `sender` field are treated as sent by
+  /// [CollabOrigin::Empty].
+  pub fn without_sender(mut self, origin: &CollabOrigin) -> Self {
+    for messages in self.0.values_mut() {
+      messages.retain(|message| &message.sender != origin);
+    }
+    self
+  }
+
+  /// Merges messages read by another `XREAD` (ie. from a different shard) into this one. Messages
+  /// of each key are expected to be ordered by id in both inputs, which is how Redis returns
+  /// them, and they stay ordered after the merge. Messages with the same id are all kept.
+  pub fn merge(&mut self, other: StreamMessageByStreamKey) {
+    for (key, theirs) in other.0 {
+      let ours = self.0.entry(key).or_default();
+      if ours.is_empty() {
+        *ours = theirs;
+        continue;
+      }
+      let mut merged = Vec::with_capacity(ours.len() + theirs.len());
+      let mut ours_iter = std::mem::take(ours).into_iter().peekable();
+      let mut theirs_iter = theirs.into_iter().peekable();
+      loop {
+        let next = match (ours_iter.peek(), theirs_iter.peek()) {
+          (Some(a), Some(b)) if b.id < a.id => theirs_iter.next(),
+          (Some(_), _) => ours_iter.next(),
+          (None, _) => theirs_iter.next(),
+        };
+        match next {
+          Some(message) => merged.push(message),
+          None => break,
+        }
+      }
+      *ours = merged;
+    }
+  }
+
+  /// Borrowing version of [StreamMessageByStreamKey::without_sender].
+  pub fn filter_sender(&self, origin: &CollabOrigin) -> BTreeMap<&str, Vec<&StreamMessage>> {
+    self
+      .0
+      .iter()
+      .map(|(key, messages)| {
+        let messages = messages
+          .iter()
+          .filter(|message| &message.sender != origin)
+          .collect();
+        (key.as_str(), messages)
+      })
+      .collect()
+  }
+}
+
 /// A message in the Redis stream. It's the same as [StreamBinary] but with additional metadata.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct StreamMessage {
   pub data: Bytes,
   /// only applicable when reading from redis
   pub id: MessageId,
+  /// Parsed from the `flags` field if the entry has one, otherwise default (v1, uncompressed).
+  pub flags: UpdateFlags,
+  /// Parsed from the `sender` field if the entry has one, otherwise [CollabOrigin::Empty].
+  pub sender: CollabOrigin,
+  /// Raw values of all entry fields (including `data`), ie. to carry arbitrary metadata like
+  /// trace context. Fields with non-binary values are skipped.
+  pub fields: HashMap<String, Bytes>,
+}
+
+impl Debug for StreamMessage {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut names: Vec<&str> = self.fields.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    let mut s = f.debug_struct("StreamMessage");
+    s.field("id", &format_args!("{}", self.id))
+      .field("flags", &format_args!("{}", self.flags))
+      .field("sender", &self.sender);
+    #[cfg(feature = "redact")]
+    s.field("data", &format_args!("<redacted>"));
+    #[cfg(not(feature = "redact"))]
+    s.field("data_len", &self.data.len());
+    s.field("fields", &names).finish()
+  }
 }
 
 impl FromRedisValue for StreamMessage {
@@ -155,438 +231,6151 @@ impl FromRedisValue for StreamMessage {
 
     let id = MessageId::from_redis_value(&bulk[0])?;
     let fields = bulk_from_redis_value(&bulk[1])?;
-    if fields.len() != 2 {
+    if fields.len() % 2 != 0 {
       return Err(RedisError::from((
         redis::ErrorKind::TypeError,
         "Invalid length",
         format!(
-          "Expected length of 2 for the bulk value, but got {}",
+          "Expected even number of key-value elements, but got {}",
           fields.len()
         ),
       )));
     }
 
-    verify_field(&fields[0], "data")?;
-    let raw_data = Vec::<u8>::from_redis_value(&fields[1])?;
+    // fields can come in any order, only `data` is required
+    let mut data = None;
+    let mut raw_fields = HashMap::with_capacity(fields.len() / 2);
+    for pair in fields.chunks_exact(2) {
+      let key = RedisString::from_redis_value(&pair[0])?.0;
+      let value = Bytes::from(Vec::<u8>::from_redis_value(&pair[1])?);
+      if key == "data" {
+        data = Some(value.clone());
+      }
+      raw_fields.insert(key, value);
+    }
+    let data = data.ok_or_else(|| {
+      RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Missing field",
+        "Expected field 'data'".to_string(),
+      ))
+    })?;
+    let flags = match raw_fields.get("flags") {
+      Some(flags) => std::str::from_utf8(flags)
+        .ok()
+        .and_then(|flags| flags.parse::<u8>().ok())
+        .unwrap_or(0)
+        .into(),
+      None => UpdateFlags::default(),
+    };
+    let sender = match raw_fields.get("sender") {
+      Some(sender) => parse_sender(sender, flags)?,
+      None => CollabOrigin::Empty,
+    };
 
     Ok(StreamMessage {
-      data: Bytes::from(raw_data),
+      data,
       id,
+      flags,
+      sender,
+      fields: raw_fields,
     })
   }
 }
 
-impl TryFrom<StreamId> for StreamMessage {
-  type Error = StreamError;
+/// Limits applied by [StreamMessage::from_redis_value_bounded] when parsing untrusted replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+  /// Max nesting depth of [Value::Bulk] values. Stream entry reply (`[id, [field, value, ...]]`)
+  /// has depth of 2.
+  pub max_depth: usize,
+  /// Max number of field-value pairs of a single stream entry.
+  pub max_fields: usize,
+}
 
-  fn try_from(value: StreamId) -> Result<Self, Self::Error> {
-    let id = MessageId::try_from(value.id.as_str())?;
-    let data = value
-      .get("data")
-      .ok_or(StreamError::UnexpectedValue("data".to_string()))?;
-    Ok(Self { data, id })
+impl Default for ParseLimits {
+  fn default() -> Self {
+    ParseLimits {
+      max_depth: 2,
+      max_fields: 64,
+    }
   }
 }
 
-#[derive(Debug)]
-pub struct StreamBinary(pub Vec<u8>);
-
-impl From<StreamMessage> for StreamBinary {
-  fn from(m: StreamMessage) -> Self {
-    Self(m.data.to_vec())
+/// Verifies that `v` doesn't nest [Value::Bulk] values deeper than `max_depth`. Traversal is
+/// iterative, so it's safe to call on arbitrarily nested values.
+fn check_depth(v: &Value, max_depth: usize) -> Result<(), StreamError> {
+  let mut stack = vec![(v, 0)];
+  while let Some((value, depth)) = stack.pop() {
+    if let Value::Bulk(values) = value {
+      if depth + 1 > max_depth {
+        return Err(StreamError::UnexpectedValue(format!(
+          "reply nested deeper than {} levels",
+          max_depth
+        )));
+      }
+      stack.extend(values.iter().map(|value| (value, depth + 1)));
+    }
   }
+  Ok(())
 }
 
-impl Deref for StreamBinary {
-  type Target = Vec<u8>;
+/// Reply of `XRANGE key id id`, used to fetch a single entry by its id. Unlike `XREAD` replies,
+/// `XRANGE` entries are not nested under their stream key. `None` if no entry with given id
+/// exists.
+#[derive(Debug, Clone)]
+pub struct SingleStreamEntry(pub Option<StreamMessage>);
 
-  fn deref(&self) -> &Self::Target {
-    &self.0
+impl FromRedisValue for SingleStreamEntry {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let entries = bulk_from_redis_value(v)?;
+    match entries.as_slice() {
+      [] => Ok(SingleStreamEntry(None)),
+      [entry] => Ok(SingleStreamEntry(Some(StreamMessage::from_redis_value(
+        entry,
+      )?))),
+      _ => Err(RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid length",
+        format!(
+          "Expected at most 1 entry in XRANGE reply, but got {}",
+          entries.len()
+        ),
+      ))),
+    }
   }
 }
 
-impl StreamBinary {
-  pub fn into_tuple_array(self) -> [(&'static str, Vec<u8>); 1] {
-    static DATA: &str = "data";
-    [(DATA, self.0)]
+/// Reply of `XREVRANGE key + - COUNT n`, used to fetch the most recent messages. Messages are
+/// kept in the reply order: newest first.
+#[derive(Debug, Clone, Default)]
+pub struct RecentMessages(pub Vec<StreamMessage>);
+
+impl RecentMessages {
+  /// Returns messages ordered from the oldest to the newest one.
+  pub fn into_chronological(self) -> Vec<StreamMessage> {
+    let mut messages = self.0;
+    messages.reverse();
+    messages
   }
 }
 
-impl TryFrom<Vec<u8>> for StreamBinary {
-  type Error = StreamError;
-
-  fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-    Ok(Self(value))
+impl FromRedisValue for RecentMessages {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    if matches!(v, Value::Nil) {
+      return Ok(RecentMessages::default());
+    }
+    let messages = bulk_from_redis_value(v)?
+      .iter()
+      .map(StreamMessage::from_redis_value)
+      .collect::<RedisResult<_>>()?;
+    Ok(RecentMessages(messages))
   }
 }
 
-impl TryFrom<&[u8]> for StreamBinary {
-  type Error = StreamError;
+impl StreamMessage {
+  /// Returns how long ago the message was added to the stream, relative to `now`. If the message
+  /// id is ahead of `now` (ie. due to a clock skew between Redis server and the caller), zero is
+  /// returned.
+  pub fn age(&self, now: SystemTime) -> Duration {
+    now
+      .duration_since(self.id.timestamp())
+      .unwrap_or(Duration::ZERO)
+  }
 
-  fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-    Ok(Self(value.to_vec()))
+  /// Lenient version of [StreamMessage::from_redis_value]: accepts entries with arbitrary fields.
+  /// If the `data` field is missing, a warning is logged and the message is returned with empty
+  /// `data`. Useful for tooling reading streams with mixed entry shapes.
+  pub fn try_from_redis_value_lossy(v: &Value) -> RedisResult<Self> {
+    let bulk = bulk_from_redis_value(v)?;
+    if bulk.len() != 2 {
+      return Err(RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid length",
+        format!(
+          "Expected length of 2 for the outer bulk value, but got:{}",
+          bulk.len()
+        ),
+      )));
+    }
+
+    let id = MessageId::from_redis_value(&bulk[0])?;
+    let fields = fields_from_redis_value(&bulk[1])?;
+    let data = match fields.get("data") {
+      Some(data) => Bytes::from(Vec::<u8>::from_redis_value(data)?),
+      None => {
+        tracing::warn!("stream entry `{}` has no `data` field", id);
+        Bytes::new()
+      },
+    };
+    let flags = match fields.get("flags") {
+      Some(flags) => u8::from_redis_value(flags).unwrap_or(0).into(),
+      None => UpdateFlags::default(),
+    };
+    let sender = match fields.get("sender") {
+      Some(sender) => parse_sender(&Vec::<u8>::from_redis_value(sender)?, flags)?,
+      None => CollabOrigin::Empty,
+    };
+    let fields = fields
+      .into_iter()
+      .filter_map(|(key, value)| match value {
+        Value::Data(value) => Some((key, Bytes::copy_from_slice(value))),
+        _ => None,
+      })
+      .collect();
+    Ok(StreamMessage {
+      data,
+      id,
+      flags,
+      sender,
+      fields,
+    })
   }
-}
 
-fn verify_field(field: &Value, expected: &str) -> RedisResult<()> {
-  let field_str = String::from_redis_value(field)?;
-  if field_str != expected {
-    return Err(RedisError::from((
-      redis::ErrorKind::TypeError,
-      "Invalid field",
-      format!("Expected '{}', found '{}'", expected, field_str),
-    )));
+  /// Hardened version of [StreamMessage::from_redis_value] for replies coming from semi-trusted
+  /// sources. Before parsing, the reply is checked against `limits`, so that adversarial replies
+  /// (deeply nested bulks or entries with huge number of fields) are rejected with an error
+  /// instead of being processed. `data`, `flags` and `sender` are parsed, and all binary fields
+  /// are kept in [StreamMessage::fields].
+  pub fn from_redis_value_bounded(v: &Value, limits: &ParseLimits) -> Result<Self, StreamError> {
+    check_depth(v, limits.max_depth)?;
+    let (id, fields) = match v {
+      Value::Bulk(bulk) if bulk.len() == 2 => (&bulk[0], &bulk[1]),
+      _ => {
+        return Err(StreamError::UnexpectedValue(format!(
+          "expecting stream entry, got {}",
+          describe_value(v)
+        )))
+      },
+    };
+    let id = MessageId::from_redis_value(id)?;
+    let fields = match fields {
+      Value::Bulk(fields) if fields.len() % 2 == 0 => fields,
+      _ => {
+        return Err(StreamError::UnexpectedValue(format!(
+          "expecting stream entry fields, got {}",
+          describe_value(fields)
+        )))
+      },
+    };
+    if fields.len() / 2 > limits.max_fields {
+      return Err(StreamError::UnexpectedValue(format!(
+        "stream entry has {} fields, up to {} are allowed",
+        fields.len() / 2,
+        limits.max_fields
+      )));
+    }
+    let mut data = None;
+    let mut flags = UpdateFlags::default();
+    let mut sender = None;
+    let mut raw_fields = HashMap::with_capacity(fields.len() / 2);
+    for pair in fields.chunks_exact(2) {
+      if let (Value::Data(key), Value::Data(value)) = (&pair[0], &pair[1]) {
+        if let Ok(key) = std::str::from_utf8(key) {
+          raw_fields.insert(key.to_string(), Bytes::copy_from_slice(value));
+        }
+      }
+      match (&pair[0], &pair[1]) {
+        (Value::Data(key), Value::Data(value)) if key == b"data" => {
+          data = Some(Bytes::copy_from_slice(value))
+        },
+        (Value::Data(key), Value::Data(value)) if key == b"flags" => {
+          flags = std::str::from_utf8(value)
+            .ok()
+            .and_then(|flags| flags.parse::<u8>().ok())
+            .unwrap_or(0)
+            .into();
+        },
+        (Value::Data(key), Value::Data(value)) if key == b"sender" => sender = Some(value),
+        (Value::Data(_), _) => {},
+        (key, _) => {
+          return Err(StreamError::UnexpectedValue(format!(
+            "expecting field name, got {}",
+            describe_value(key)
+          )))
+        },
+      }
+    }
+    let data = data.ok_or_else(|| StreamError::UnexpectedValue("data".to_string()))?;
+    // sender form depends on flags, which may come after it
+    let sender = match sender {
+      Some(sender) => parse_sender(sender, flags)?,
+      None => CollabOrigin::Empty,
+    };
+    Ok(StreamMessage {
+      data,
+      id,
+      flags,
+      sender,
+      fields: raw_fields,
+    })
   }
-  Ok(())
-}
 
-pub struct RedisString(String);
-impl FromRedisValue for RedisString {
-  fn from_redis_value(v: &Value) -> RedisResult<Self> {
-    match v {
-      Value::Data(bytes) => Ok(RedisString(String::from_utf8(bytes.to_vec())?)),
-      _ => Err(internal("expecting Value::Data")),
+  /// Decodes the message payload as yrs update, decompressing and choosing the decoder according
+  /// to message `flags`. Entries without `flags` field are treated as v1 encoded, uncompressed.
+  pub fn into_yrs_update(self) -> Result<collab::preclude::Update, StreamError> {
+    self.verify_checksum()?;
+    decode_update(&self.data, self.flags)
+  }
+
+  /// Verifies `data` against the `checksum` field written by
+  /// [CollabStreamUpdate::to_fields_with_checksum]. Messages without checksum are accepted.
+  pub fn verify_checksum(&self) -> Result<(), StreamError> {
+    match self.fields.get("checksum") {
+      Some(checksum) => verify_checksum(&self.data, std::str::from_utf8(checksum)?.parse()?),
+      None => Ok(()),
     }
   }
-}
 
-impl Display for RedisString {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.0.clone())
+  /// Returns the entry id in its canonical `{timestamp}-{sequence}` string form. For ids
+  /// generated by Redis (which never contain leading zeros or signs), this is exactly the id
+  /// string Redis sent, so it can be passed back verbatim.
+  pub fn raw_id(&self) -> String {
+    self.id.to_string()
+  }
+
+  /// Returns raw values of all fields of the stream entry, including `data`, ie. to carry
+  /// arbitrary metadata like trace context. Fields with non-binary values are skipped.
+  pub fn fields(&self) -> &HashMap<String, Bytes> {
+    &self.fields
+  }
+
+  /// Returns raw value of the field `name`, if the entry has one.
+  pub fn field_bytes(&self, name: &str) -> Option<&Bytes> {
+    self.fields.get(name)
+  }
+
+  /// Returns value of the field `name`, if the entry has one and it's valid UTF-8.
+  pub fn field_str(&self, name: &str) -> Option<&str> {
+    std::str::from_utf8(self.fields.get(name)?).ok()
+  }
+
+  /// Compares messages by their [MessageId] only, ie. to merge messages from multiple reads.
+  pub fn cmp_by_id(&self, other: &Self) -> std::cmp::Ordering {
+    self.id.cmp(&other.id)
+  }
+
+  /// Splits the message into its payload and id. Unlike `From<StreamMessage> for StreamBinary`,
+  /// it keeps the [MessageId] around, ie. so that message can be acknowledged after the payload
+  /// has been processed.
+  pub fn into_binary_with_id(self) -> (StreamBinary, MessageId) {
+    let id = self.id;
+    (StreamBinary::from(self), id)
   }
 }
 
-fn bulk_from_redis_value(v: &Value) -> Result<&Vec<Value>, RedisError> {
-  match v {
-    Value::Bulk(b) => Ok(b),
-    _ => Err(internal("expecting Value::Bulk")),
+impl StreamMessage {
+  /// Decodes the message payload as [CollabUpdateEvent] and turns it into a validated
+  /// [CollabStreamUpdate] sent by the message sender. If the payload cannot be decoded, a
+  /// [DeadLetter] carrying the raw payload is returned instead, so that the message can be moved
+  /// into a dead letter stream rather than stopping the whole pipeline.
+  pub fn into_collab_update_or_dead_letter(
+    self,
+    key: &str,
+  ) -> Result<CollabStreamUpdate, DeadLetter> {
+    let result = self
+      .verify_checksum()
+      .and_then(|_| CollabUpdateEvent::decode(&self.data))
+      .and_then(|event| match event {
+        CollabUpdateEvent::UpdateV1 { encode_update } => CollabStreamUpdate::new_validated(
+          encode_update,
+          self.sender.clone(),
+          UpdateFlags::default(),
+        ),
+      });
+    result.map_err(|err| DeadLetter {
+      id: self.id,
+      key: key.to_string(),
+      reason: err.to_string(),
+      raw: self.data,
+    })
   }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum CollabControlEvent {
-  Open {
-    workspace_id: String,
-    object_id: String,
-    collab_type: CollabType,
-    doc_state: Vec<u8>,
-  },
-  Close {
-    object_id: String,
-  },
+/// Sorts messages by their [MessageId], from the oldest to the newest one. Messages with equal ids
+/// keep their relative order.
+pub fn sort_by_id(messages: &mut [StreamMessage]) {
+  messages.sort_by(StreamMessage::cmp_by_id);
 }
 
-impl Display for CollabControlEvent {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    match self {
-      CollabControlEvent::Open {
-        workspace_id: _,
-        object_id,
-        collab_type,
-        doc_state: _,
-      } => f.write_fmt(format_args!(
-        "Open collab: object_id:{}|collab_type:{:?}",
-        object_id, collab_type,
-      )),
-      CollabControlEvent::Close { object_id } => {
-        f.write_fmt(format_args!("Close collab: object_id:{}", object_id))
-      },
-    }
-  }
+/// Stream message that couldn't be decoded, together with the reason of failure and its raw
+/// payload, so that it can be quarantined and inspected later.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+  pub id: MessageId,
+  pub key: String,
+  pub reason: String,
+  pub raw: Bytes,
 }
 
-impl CollabControlEvent {
-  pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
-    serde_json::to_vec(self)
+impl Debug for DeadLetter {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut s = f.debug_struct("DeadLetter");
+    s.field("id", &format_args!("{}", self.id))
+      .field("key", &self.key)
+      .field("reason", &self.reason);
+    #[cfg(feature = "redact")]
+    s.field("raw", &format_args!("<redacted>"));
+    #[cfg(not(feature = "redact"))]
+    s.field("raw_len", &self.raw.len());
+    s.finish()
   }
+}
 
-  pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
-    serde_json::from_slice(data)
+impl Display for DeadLetter {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    #[cfg(feature = "redact")]
+    return write!(
+      f,
+      "dead letter {} from `{}`: {}",
+      self.id, self.key, self.reason
+    );
+    #[cfg(not(feature = "redact"))]
+    write!(
+      f,
+      "dead letter {} from `{}` ({} bytes): {}",
+      self.id,
+      self.key,
+      self.raw.len(),
+      self.reason
+    )
   }
 }
 
-impl TryFrom<CollabControlEvent> for StreamBinary {
+impl TryFrom<StreamId> for StreamMessage {
   type Error = StreamError;
 
-  fn try_from(value: CollabControlEvent) -> Result<Self, Self::Error> {
-    let raw_data = value.encode()?;
-    Ok(StreamBinary(raw_data))
+  fn try_from(value: StreamId) -> Result<Self, Self::Error> {
+    Self::try_from(&value)
   }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum CollabUpdateEvent {
-  UpdateV1 { encode_update: Vec<u8> },
-}
+impl TryFrom<&StreamId> for StreamMessage {
+  type Error = StreamError;
 
-impl CollabUpdateEvent {
-  #[allow(dead_code)]
-  fn to_proto(&self) -> proto::collab::CollabUpdateEvent {
-    match self {
-      CollabUpdateEvent::UpdateV1 { encode_update } => proto::collab::CollabUpdateEvent {
-        update: Some(Update::UpdateV1(encode_update.clone())),
-      },
+  /// Parses the message without consuming [StreamId], copying only its `data`, `flags` and
+  /// `sender` fields.
+  fn try_from(value: &StreamId) -> Result<Self, Self::Error> {
+    let id = MessageId::try_from(value.id.as_str())?;
+    let data = value
+      .get("data")
+      .ok_or(StreamError::UnexpectedValue("data".to_string()))?;
+    let flags = value.get::<u8>("flags").unwrap_or(0).into();
+    let sender = match value.get::<Vec<u8>>("sender") {
+      Some(sender) => parse_sender(&sender, flags)?,
+      None => CollabOrigin::Empty,
+    };
+    let fields = value
+      .map
+      .iter()
+      .filter_map(|(key, value)| match value {
+        Value::Data(value) => Some((key.clone(), Bytes::copy_from_slice(value))),
+        _ => None,
+      })
+      .collect();
+    Ok(Self {
+      data,
+      id,
+      flags,
+      sender,
+      fields,
+    })
+  }
+}
+
+/// Reference to a message read by a consumer group, carrying everything needed to `XACK` it.
+///
+/// When used as redis args it lays out `key group id`, which is the exact argument list expected
+/// by `XACK`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckRef {
+  pub key: String,
+  pub group: String,
+  pub id: MessageId,
+}
+
+impl AckRef {
+  pub fn new<K, G>(key: K, group: G, id: MessageId) -> Self
+  where
+    K: Into<String>,
+    G: Into<String>,
+  {
+    AckRef {
+      key: key.into(),
+      group: group.into(),
+      id,
     }
   }
+}
 
-  fn from_proto(proto: &proto::collab::CollabUpdateEvent) -> Result<Self, StreamError> {
-    match &proto.update {
-      None => Err(StreamError::UnexpectedValue(
-        "update not set for CollabUpdateEvent proto".to_string(),
-      )),
-      Some(update) => match update {
-        Update::UpdateV1(encode_update) => Ok(CollabUpdateEvent::UpdateV1 {
-          encode_update: encode_update.to_vec(),
-        }),
-      },
+impl ToRedisArgs for AckRef {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    self.key.write_redis_args(out);
+    self.group.write_redis_args(out);
+    self.id.write_redis_args(out);
+  }
+}
+
+/// A batch of [AckRef]s grouped by `(key, group)`, so that each group can be acknowledged with
+/// a single `XACK` command. Repeated ids within the same group are deduplicated.
+#[derive(Debug, Clone, Default)]
+pub struct AckBatch {
+  ids: BTreeMap<(String, String), BTreeSet<MessageId>>,
+}
+
+impl AckBatch {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds message to the batch. Returns `false` if the same message was already present.
+  pub fn push(&mut self, ack: AckRef) -> bool {
+    self
+      .ids
+      .entry((ack.key, ack.group))
+      .or_default()
+      .insert(ack.id)
+  }
+
+  /// Total number of unique message ids in the batch.
+  pub fn len(&self) -> usize {
+    self.ids.values().map(|ids| ids.len()).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ids.is_empty()
+  }
+
+  /// Returns an iterator over all `(key, group)` pairs, each with its own set of message ids.
+  pub fn groups(&self) -> impl Iterator<Item = AckGroup<'_>> {
+    self
+      .ids
+      .iter()
+      .map(|((key, group), ids)| AckGroup { key, group, ids })
+  }
+
+  /// Builds a pipeline with one `XACK` command per `(key, group)` pair.
+  pub fn pipeline(&self) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    for group in self.groups() {
+      pipe.cmd("XACK").arg(group);
     }
+    pipe
   }
+}
 
-  pub fn encode(&self) -> Vec<u8> {
-    self.to_proto().encode_to_vec()
+impl FromIterator<AckRef> for AckBatch {
+  fn from_iter<T: IntoIterator<Item = AckRef>>(iter: T) -> Self {
+    let mut batch = AckBatch::new();
+    for ack in iter {
+      batch.push(ack);
+    }
+    batch
   }
+}
 
-  pub fn decode(data: &[u8]) -> Result<Self, StreamError> {
-    match prost::Message::decode(data) {
-      Ok(proto) => CollabUpdateEvent::from_proto(&proto),
-      Err(_) => match bincode::deserialize(data) {
-        Ok(event) => Ok(event),
-        Err(e) => Err(StreamError::BinCodeSerde(e)),
-      },
+/// All message ids of an [AckBatch] sharing the same `(key, group)`. When used as redis args it
+/// lays out `key group id1 id2 ...`, as expected by `XACK`.
+#[derive(Debug, Clone, Copy)]
+pub struct AckGroup<'a> {
+  pub key: &'a str,
+  pub group: &'a str,
+  pub ids: &'a BTreeSet<MessageId>,
+}
+
+impl ToRedisArgs for AckGroup<'_> {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    self.key.write_redis_args(out);
+    self.group.write_redis_args(out);
+    for id in self.ids {
+      id.write_redis_args(out);
     }
   }
 }
 
-impl TryFrom<CollabUpdateEvent> for StreamBinary {
-  type Error = StreamError;
+/// Position in the stream from which stream commands (like `XGROUP CREATE` or `XREAD`) should
+/// start delivering messages.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum StreamReadId {
+  /// `$`: deliver only messages added after the command has been issued.
+  #[default]
+  NewMessages,
+  /// `0`: deliver all messages from the beginning of the stream.
+  Beginning,
+  /// Deliver messages with ids greater than the given one.
+  After(MessageId),
+}
 
-  fn try_from(value: CollabUpdateEvent) -> Result<Self, Self::Error> {
-    let raw_data = value.encode();
-    Ok(StreamBinary(raw_data))
+impl ToRedisArgs for StreamReadId {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    match self {
+      StreamReadId::NewMessages => out.write_arg(b"$"),
+      StreamReadId::Beginning => out.write_arg(b"0"),
+      StreamReadId::After(id) => id.write_redis_args(out),
+    }
   }
 }
 
-pub struct CollabStreamUpdate {
-  pub data: Vec<u8>, // yrs::Update::encode_v1
-  pub sender: CollabOrigin,
-  pub flags: UpdateFlags,
+/// Arguments of `XGROUP CREATE key group id [MKSTREAM]`. When used as redis args it lays out
+/// everything following the `XGROUP` command name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupCreate {
+  pub key: String,
+  pub group: String,
+  /// Id of the last message considered already delivered to the group. Default: `$`.
+  pub start: StreamReadId,
+  /// If set, the stream is created if it doesn't exist yet.
+  pub mkstream: bool,
 }
 
-impl CollabStreamUpdate {
-  pub fn new<B, F>(data: B, sender: CollabOrigin, flags: F) -> Self
+impl GroupCreate {
+  pub fn new<K, G>(key: K, group: G) -> Self
   where
-    B: Into<Vec<u8>>,
-    F: Into<UpdateFlags>,
+    K: Into<String>,
+    G: Into<String>,
   {
-    CollabStreamUpdate {
-      data: data.into(),
-      sender,
-      flags: flags.into(),
+    GroupCreate {
+      key: key.into(),
+      group: group.into(),
+      start: StreamReadId::default(),
+      mkstream: false,
     }
   }
 
-  /// Returns Redis stream key, that's storing entries mapped to/from [CollabStreamUpdate].
-  pub fn stream_key(workspace_id: &str, object_id: &str) -> String {
-    // use `:` separator as it adheres to Redis naming conventions
-    format!("af:{}:{}:updates", workspace_id, object_id)
+  pub fn with_start(mut self, start: StreamReadId) -> Self {
+    self.start = start;
+    self
   }
 
-  pub fn into_update(self) -> Result<collab::preclude::Update, StreamError> {
-    let bytes = if self.flags.is_compressed() {
-      zstd::decode_all(std::io::Cursor::new(self.data))?
-    } else {
-      self.data
-    };
-    let update = if self.flags.is_v1_encoded() {
-      collab::preclude::Update::decode_v1(&bytes)?
-    } else {
-      collab::preclude::Update::decode_v2(&bytes)?
-    };
-    Ok(update)
+  pub fn with_mkstream(mut self) -> Self {
+    self.mkstream = true;
+    self
   }
-}
 
-impl TryFrom<HashMap<String, redis::Value>> for CollabStreamUpdate {
-  type Error = StreamError;
+  /// Returns complete `XGROUP CREATE` command.
+  pub fn cmd(&self) -> redis::Cmd {
+    let mut cmd = redis::cmd("XGROUP");
+    cmd.arg(self);
+    cmd
+  }
+}
 
-  fn try_from(fields: HashMap<String, Value>) -> Result<Self, Self::Error> {
-    let sender = match fields.get("sender") {
-      None => CollabOrigin::Empty,
-      Some(sender) => {
-        let raw_origin = String::from_redis_value(sender)?;
-        collab_origin_from_str(&raw_origin)?
-      },
-    };
-    let flags = match fields.get("flags") {
-      None => UpdateFlags::default(),
-      Some(flags) => u8::from_redis_value(flags).unwrap_or(0).into(),
-    };
-    let data_raw = fields
-      .get("data")
-      .ok_or_else(|| internal("expecting field `data`"))?;
-    let data: Vec<u8> = FromRedisValue::from_redis_value(data_raw)?;
-    Ok(CollabStreamUpdate {
-      data,
-      sender,
-      flags,
-    })
+impl ToRedisArgs for GroupCreate {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    out.write_arg(b"CREATE");
+    self.key.write_redis_args(out);
+    self.group.write_redis_args(out);
+    self.start.write_redis_args(out);
+    if self.mkstream {
+      out.write_arg(b"MKSTREAM");
+    }
   }
 }
 
-pub struct AwarenessStreamUpdate {
-  pub data: Vec<u8>, // AwarenessUpdate::encode_v1
-  pub sender: CollabOrigin,
+/// Arguments of `XREAD [COUNT count] [BLOCK ms] STREAMS key [key ...] id [id ...]`, reading
+/// multiple streams at once. When used as redis args it lays out everything following the
+/// `XREAD` command name, with all keys first and then all ids in the same order. Reply can be
+/// parsed as [StreamMessageByStreamKey], or as [ReadOutcome] when blocking.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiStreamRead {
+  pub entries: Vec<(String, StreamReadId)>,
+  pub count: Option<usize>,
+  pub block_ms: Option<u64>,
 }
 
-impl AwarenessStreamUpdate {
-  /// Returns Redis stream key, that's storing entries mapped to/from [AwarenessStreamUpdate].
-  pub fn stream_key(workspace_id: &str, object_id: &str) -> String {
-    format!("af:{}:{}:awareness", workspace_id, object_id)
+impl MultiStreamRead {
+  pub fn new() -> Self {
+    Self::default()
   }
-}
 
-impl TryFrom<HashMap<String, redis::Value>> for AwarenessStreamUpdate {
-  type Error = StreamError;
+  pub fn with_stream<K: Into<String>>(mut self, key: K, id: StreamReadId) -> Self {
+    self.entries.push((key.into(), id));
+    self
+  }
 
-  fn try_from(fields: HashMap<String, Value>) -> Result<Self, Self::Error> {
-    let sender = match fields.get("sender") {
-      None => CollabOrigin::Empty,
-      Some(sender) => {
-        let raw_origin = String::from_redis_value(sender)?;
-        collab_origin_from_str(&raw_origin)?
-      },
-    };
-    let data_raw = fields
-      .get("data")
-      .ok_or_else(|| internal("expecting field `data`"))?;
-    let data: Vec<u8> = FromRedisValue::from_redis_value(data_raw)?;
-    Ok(AwarenessStreamUpdate { data, sender })
+  pub fn with_count(mut self, count: usize) -> Self {
+    self.count = Some(count);
+    self
+  }
+
+  pub fn with_block_ms(mut self, block_ms: u64) -> Self {
+    self.block_ms = Some(block_ms);
+    self
+  }
+
+  /// Returns complete `XREAD` command.
+  pub fn cmd(&self) -> redis::Cmd {
+    let mut cmd = redis::cmd("XREAD");
+    cmd.arg(self);
+    cmd
   }
 }
 
-//FIXME: this should be `impl FromStr for CollabOrigin`
-fn collab_origin_from_str(value: &str) -> RedisResult<CollabOrigin> {
-  match value {
-    "" => Ok(CollabOrigin::Empty),
-    "server" => Ok(CollabOrigin::Server),
-    other => {
-      let mut split = other.split('|');
-      match (split.next(), split.next()) {
-        (Some(uid), Some(device_id)) | (Some(device_id), Some(uid))
-          if uid.starts_with("uid:") && device_id.starts_with("device_id:") =>
-        {
-          let uid = uid.trim_start_matches("uid:");
-          let device_id = device_id.trim_start_matches("device_id:").to_string();
-          let uid: i64 = uid
-            .parse()
-            .map_err(|err| internal(format!("failed to parse uid: {}", err)))?;
-          Ok(CollabOrigin::Client(CollabClient { uid, device_id }))
-        },
-        _ => Err(internal(format!(
-          "couldn't parse collab origin from `{}`",
-          other
-        ))),
-      }
-    },
+impl ToRedisArgs for MultiStreamRead {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    if let Some(count) = self.count {
+      out.write_arg(b"COUNT");
+      count.write_redis_args(out);
+    }
+    if let Some(block_ms) = self.block_ms {
+      out.write_arg(b"BLOCK");
+      block_ms.write_redis_args(out);
+    }
+    out.write_arg(b"STREAMS");
+    for (key, _) in &self.entries {
+      key.write_redis_args(out);
+    }
+    for (_, id) in &self.entries {
+      id.write_redis_args(out);
+    }
   }
 }
 
-#[repr(transparent)]
-#[derive(Copy, Clone, Eq, PartialEq, Default)]
-pub struct UpdateFlags(u8);
+/// Options of `XADD key [NOMKSTREAM] [MAXLEN [~|=] threshold] *`. When used as redis args it lays
+/// out everything between the stream key and the entry fields, including the `*` id.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AppendOptions {
+  /// Trims the stream to given number of entries while appending. If the flag is `true`, the
+  /// trimming is approximate (`~`), which is much more efficient.
+  pub maxlen: Option<(bool, u64)>,
+  /// If set, the stream is not created if it doesn't exist yet and nothing is appended.
+  pub nomkstream: bool,
+  /// If set, the entry gets a `checksum` field, see [CollabStreamUpdate::to_fields_with_checksum].
+  pub checksum: bool,
+}
 
-impl UpdateFlags {
-  /// Flag bit to mark if update is encoded using [EncoderV2] (if set) or [EncoderV1] (if clear).
-  pub const IS_V2_ENCODED: u8 = 0b0000_0001;
-  /// Flag bit to mark if update is compressed.
-  pub const IS_COMPRESSED: u8 = 0b0000_0010;
+impl AppendOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
 
-  #[inline]
-  pub fn is_v2_encoded(&self) -> bool {
-    self.0 & Self::IS_V2_ENCODED != 0
+  pub fn with_maxlen(mut self, maxlen: u64) -> Self {
+    self.maxlen = Some((false, maxlen));
+    self
   }
 
-  #[inline]
-  pub fn is_v1_encoded(&self) -> bool {
-    !self.is_v2_encoded()
+  pub fn with_approx_maxlen(mut self, maxlen: u64) -> Self {
+    self.maxlen = Some((true, maxlen));
+    self
   }
 
-  #[inline]
-  pub fn is_compressed(&self) -> bool {
-    self.0 & Self::IS_COMPRESSED != 0
+  pub fn with_nomkstream(mut self) -> Self {
+    self.nomkstream = true;
+    self
+  }
+
+  pub fn with_checksum(mut self) -> Self {
+    self.checksum = true;
+    self
+  }
+
+  /// Returns complete `XADD` command appending `update` to the stream `key`. Update fields are
+  /// laid out in [CollabStreamUpdate::to_fields] order, followed by `checksum` if enabled.
+  pub fn cmd(&self, key: &str, update: &CollabStreamUpdate) -> redis::Cmd {
+    let fields = if self.checksum {
+      update.to_fields_with_checksum()
+    } else {
+      update.to_fields()
+    };
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(key).arg(self).arg(fields);
+    cmd
   }
 }
 
-impl ToRedisArgs for UpdateFlags {
-  #[inline]
+impl ToRedisArgs for AppendOptions {
   fn write_redis_args<W>(&self, out: &mut W)
   where
     W: ?Sized + RedisWrite,
   {
-    self.0.write_redis_args(out)
+    if self.nomkstream {
+      out.write_arg(b"NOMKSTREAM");
+    }
+    if let Some((approx, maxlen)) = self.maxlen {
+      out.write_arg(b"MAXLEN");
+      out.write_arg(if approx { b"~" } else { b"=" });
+      maxlen.write_redis_args(out);
+    }
+    out.write_arg(b"*");
   }
 }
 
-impl From<u8> for UpdateFlags {
-  #[inline]
-  fn from(value: u8) -> Self {
-    UpdateFlags(value)
+/// Single entry of the extended form of `XPENDING key group [IDLE min-idle] start end count`
+/// reply. Each entry is returned as `[id, consumer, idle_ms, delivery_count]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEntry {
+  pub id: MessageId,
+  /// Name of the consumer that currently owns the message.
+  pub consumer: String,
+  /// Number of milliseconds that passed since the last time the message was delivered.
+  pub idle_ms: u64,
+  /// Number of times the message was delivered.
+  pub delivery_count: u64,
+}
+
+impl PendingEntry {
+  /// Time passed since the message was last delivered to a consumer.
+  pub fn idle(&self) -> Duration {
+    Duration::from_millis(self.idle_ms)
   }
 }
 
-impl Display for UpdateFlags {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    if !self.is_v2_encoded() {
-      write!(f, ".v1")?;
-    } else {
-      write!(f, ".v2")?;
+impl FromRedisValue for PendingEntry {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let bulk = bulk_from_redis_value(v)?;
+    if bulk.len() != 4 {
+      return Err(RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid length",
+        format!(
+          "Expected length of 4 for the pending entry, but got {}",
+          bulk.len()
+        ),
+      )));
+    }
+
+    Ok(PendingEntry {
+      id: MessageId::from_redis_value(&bulk[0])?,
+      consumer: RedisString::from_redis_value(&bulk[1])?.0,
+      idle_ms: u64::from_redis_value(&bulk[2])?,
+      delivery_count: u64::from_redis_value(&bulk[3])?,
+    })
+  }
+}
+
+/// Summary form of `XPENDING key group` reply: total number of pending messages, the smallest
+/// and greatest pending message ids and number of pending messages per consumer.
+///
+/// When there are no pending messages, `min_id`/`max_id` are `None` and `consumers` is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingSummary {
+  pub count: u64,
+  pub min_id: Option<MessageId>,
+  pub max_id: Option<MessageId>,
+  /// Pairs of consumer name and number of messages pending for that consumer.
+  pub consumers: Vec<(String, u64)>,
+}
+
+impl FromRedisValue for PendingSummary {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let bulk = bulk_from_redis_value(v)?;
+    if bulk.len() != 4 {
+      return Err(RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid length",
+        format!(
+          "Expected length of 4 for the pending summary, but got {}",
+          bulk.len()
+        ),
+      )));
     }
 
-    if self.is_compressed() {
-      write!(f, ".zstd")?;
+    let count = u64::from_redis_value(&bulk[0])?;
+    let min_id = Option::<MessageId>::from_redis_value(&bulk[1])?;
+    let max_id = Option::<MessageId>::from_redis_value(&bulk[2])?;
+    let mut consumers = Vec::new();
+    if !matches!(bulk[3], Value::Nil) {
+      for consumer in bulk_from_redis_value(&bulk[3])? {
+        let pair = bulk_from_redis_value(consumer)?;
+        if pair.len() != 2 {
+          return Err(RedisError::from((
+            redis::ErrorKind::TypeError,
+            "Invalid length",
+            format!(
+              "Expected length of 2 for the pending consumer, but got {}",
+              pair.len()
+            ),
+          )));
+        }
+        let name = RedisString::from_redis_value(&pair[0])?.0;
+        // consumer's pending count is returned as a bulk string
+        let pending = u64::from_redis_value(&pair[1])?;
+        consumers.push((name, pending));
+      }
     }
 
-    Ok(())
+    Ok(PendingSummary {
+      count,
+      min_id,
+      max_id,
+      consumers,
+    })
   }
 }
 
-#[cfg(test)]
-mod test {
-  use crate::model::collab_origin_from_str;
-  use collab::core::origin::{CollabClient, CollabOrigin};
+/// Reply of `XAUTOCLAIM key group consumer min-idle-time start`.
+///
+/// Redis replies with a cursor that should be used as `start` of the next `XAUTOCLAIM` call,
+/// a list of claimed messages and (since Redis 7.0) a list of ids of messages that were pending
+/// but no longer exist in the stream.
+#[derive(Debug, Clone)]
+pub struct AutoClaimReply {
+  pub next_cursor: MessageId,
+  pub messages: Vec<StreamMessage>,
+  pub deleted: Vec<MessageId>,
+}
 
-  #[test]
-  fn parse_collab_origin_empty() {
-    let expected = CollabOrigin::Empty;
-    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
-    assert_eq!(actual, expected);
+impl AutoClaimReply {
+  /// Returns true if the whole pending entries list has been scanned, which Redis signals by
+  /// returning `0-0` as the next cursor.
+  pub fn is_complete(&self) -> bool {
+    self.next_cursor == MessageId::MIN
   }
+}
 
-  #[test]
-  fn parse_collab_origin_server() {
-    let expected = CollabOrigin::Server;
-    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
-    assert_eq!(actual, expected);
+impl FromRedisValue for AutoClaimReply {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let bulk = bulk_from_redis_value(v)?;
+    // Redis 6.2 replies with 2 elements, Redis 7.0+ adds the list of deleted ids
+    if bulk.len() != 2 && bulk.len() != 3 {
+      return Err(RedisError::from((
+        redis::ErrorKind::TypeError,
+        "Invalid length",
+        format!(
+          "Expected length of 2 or 3 for the XAUTOCLAIM reply, but got {}",
+          bulk.len()
+        ),
+      )));
+    }
+
+    let next_cursor = MessageId::from_redis_value(&bulk[0])?;
+    let entries = bulk_from_redis_value(&bulk[1])?;
+    let mut messages = Vec::with_capacity(entries.len());
+    for entry in entries {
+      // Redis 6.2 returns nil in place of the entries that were deleted from the stream
+      if !matches!(entry, Value::Nil) {
+        messages.push(StreamMessage::from_redis_value(entry)?);
+      }
+    }
+    let deleted = match bulk.get(2) {
+      Some(deleted) => Vec::<MessageId>::from_redis_value(deleted)?,
+      None => Vec::new(),
+    };
+
+    Ok(AutoClaimReply {
+      next_cursor,
+      messages,
+      deleted,
+    })
+  }
+}
+
+/// Stream metadata returned by `XINFO STREAM key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+  /// Number of entries in the stream.
+  pub length: u64,
+  /// Id of the first entry in the stream. `None` if the stream is empty.
+  pub first_entry: Option<MessageId>,
+  /// Id of the last entry in the stream. `None` if the stream is empty.
+  pub last_entry: Option<MessageId>,
+  /// Id of the most recently added entry. It's retained even if that entry was deleted.
+  pub last_generated_id: MessageId,
+  /// Number of consumer groups defined for the stream.
+  pub groups: u64,
+}
+
+impl FromRedisValue for StreamInfo {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let fields = fields_from_redis_value(v)?;
+    let length = u64::from_redis_value(required_field(&fields, "length")?)?;
+    let last_generated_id =
+      MessageId::from_redis_value(required_field(&fields, "last-generated-id")?)?;
+    let groups = u64::from_redis_value(required_field(&fields, "groups")?)?;
+    let first_entry = match fields.get("first-entry") {
+      Some(entry) => entry_id_from_redis_value(entry)?,
+      None => None,
+    };
+    let last_entry = match fields.get("last-entry") {
+      Some(entry) => entry_id_from_redis_value(entry)?,
+      None => None,
+    };
+    Ok(StreamInfo {
+      length,
+      first_entry,
+      last_entry,
+      last_generated_id,
+      groups,
+    })
+  }
+}
+
+/// Reply of `XLEN key`: number of entries in the stream. It's `0` for streams which don't exist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamLen(pub u64);
+
+impl FromRedisValue for StreamLen {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    match v {
+      Value::Int(len) => u64::try_from(*len)
+        .map(StreamLen)
+        .map_err(|_| internal(format!("expecting non-negative stream length, got {}", len))),
+      _ => Err(internal(format!(
+        "expecting Value::Int, got {}",
+        describe_value(v)
+      ))),
+    }
+  }
+}
+
+/// Subset of `XINFO STREAM key` reply useful for coarse monitoring of stream trimming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+  /// Number of entries in the stream.
+  pub length: u64,
+  /// Id of the most recent entry removed from the stream, by `XDEL` or trimming. `None` if no
+  /// entry was ever removed, or if Redis is older than 7.0 which doesn't report it.
+  pub max_deleted_id: Option<MessageId>,
+}
+
+impl FromRedisValue for StreamStats {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let fields = fields_from_redis_value(v)?;
+    let length = u64::from_redis_value(required_field(&fields, "length")?)?;
+    let max_deleted_id = match fields.get("max-deleted-entry-id") {
+      Some(id) => Some(MessageId::from_redis_value(id)?).filter(|id| *id != MessageId::MIN),
+      None => None,
+    };
+    Ok(StreamStats {
+      length,
+      max_deleted_id,
+    })
+  }
+}
+
+/// Consumer group metadata returned by `XINFO GROUPS key`. The reply itself can be parsed as
+/// `Vec<GroupInfo>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupInfo {
+  pub name: String,
+  /// Number of consumers in the group.
+  pub consumers: u64,
+  /// Number of entries delivered to the group, but not acknowledged yet.
+  pub pending: u64,
+  /// Id of the last entry delivered to the group.
+  pub last_delivered_id: MessageId,
+  /// Number of entries in the stream still waiting to be delivered to the group. `None` if it
+  /// cannot be determined, or if Redis is older than 7.0 which doesn't report it.
+  pub lag: Option<u64>,
+}
+
+impl FromRedisValue for GroupInfo {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    let fields = fields_from_redis_value(v)?;
+    let name = RedisString::from_redis_value(required_field(&fields, "name")?)?.0;
+    let consumers = u64::from_redis_value(required_field(&fields, "consumers")?)?;
+    let pending = u64::from_redis_value(required_field(&fields, "pending")?)?;
+    let last_delivered_id =
+      MessageId::from_redis_value(required_field(&fields, "last-delivered-id")?)?;
+    let lag = match fields.get("lag") {
+      Some(Value::Nil) | None => None,
+      Some(lag) => Some(u64::from_redis_value(lag)?),
+    };
+    Ok(GroupInfo {
+      name,
+      consumers,
+      pending,
+      last_delivered_id,
+      lag,
+    })
+  }
+}
+
+/// Parses an id of a `[id, [field, value, ...]]` stream entry. Returns `None` for nil entries.
+fn entry_id_from_redis_value(v: &Value) -> RedisResult<Option<MessageId>> {
+  if matches!(v, Value::Nil) {
+    return Ok(None);
+  }
+  let entry = bulk_from_redis_value(v)?;
+  match entry.first() {
+    Some(id) => Ok(Some(MessageId::from_redis_value(id)?)),
+    None => Err(internal("expecting stream entry id, got empty Value::Bulk")),
+  }
+}
+
+#[derive(Debug)]
+pub struct StreamBinary(pub Vec<u8>);
+
+impl From<StreamMessage> for StreamBinary {
+  fn from(m: StreamMessage) -> Self {
+    Self(m.data.to_vec())
+  }
+}
+
+impl Deref for StreamBinary {
+  type Target = Vec<u8>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.0
+  }
+}
+
+impl StreamBinary {
+  pub fn into_tuple_array(self) -> [(&'static str, Vec<u8>); 1] {
+    static DATA: &str = "data";
+    [(DATA, self.0)]
+  }
+}
+
+impl TryFrom<Vec<u8>> for StreamBinary {
+  type Error = StreamError;
+
+  fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+    Ok(Self(value))
+  }
+}
+
+impl TryFrom<&[u8]> for StreamBinary {
+  type Error = StreamError;
+
+  fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+    Ok(Self(value.to_vec()))
+  }
+}
+
+pub struct RedisString(String);
+
+impl RedisString {
+  /// Max number of bytes of invalid string included in the error message.
+  const PREVIEW_LEN: usize = 32;
+
+  /// Same as [RedisString::from_redis_value], but replaces invalid UTF-8 sequences with
+  /// `U+FFFD`, ie. to print binary stream keys for diagnostics.
+  pub fn from_redis_value_lossy(v: &Value) -> RedisResult<Self> {
+    match v {
+      Value::Data(bytes) => Ok(RedisString(String::from_utf8_lossy(bytes).into_owned())),
+      _ => Err(internal(format!(
+        "expecting Value::Data, got {}",
+        describe_value(v)
+      ))),
+    }
+  }
+}
+
+impl FromRedisValue for RedisString {
+  fn from_redis_value(v: &Value) -> RedisResult<Self> {
+    match v {
+      Value::Data(bytes) => match String::from_utf8(bytes.to_vec()) {
+        Ok(string) => Ok(RedisString(string)),
+        Err(err) => {
+          let preview = &bytes[..bytes.len().min(Self::PREVIEW_LEN)];
+          Err(internal(format!(
+            "invalid UTF-8 string of {} bytes (valid up to {}): {:?}",
+            bytes.len(),
+            err.utf8_error().valid_up_to(),
+            String::from_utf8_lossy(preview)
+          )))
+        },
+      },
+      _ => Err(internal(format!(
+        "expecting Value::Data, got {}",
+        describe_value(v)
+      ))),
+    }
+  }
+}
+
+impl Display for RedisString {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0.clone())
+  }
+}
+
+fn bulk_from_redis_value(v: &Value) -> Result<&Vec<Value>, RedisError> {
+  match v {
+    Value::Bulk(b) => Ok(b),
+    _ => Err(internal(format!(
+      "expecting Value::Bulk, got {}",
+      describe_value(v)
+    ))),
+  }
+}
+
+/// Parses a flat `[key1, value1, key2, value2, ...]` reply (used ie. by `XINFO` commands) into
+/// a map of values by their keys.
+fn fields_from_redis_value(v: &Value) -> RedisResult<HashMap<String, &Value>> {
+  let bulk = bulk_from_redis_value(v)?;
+  if bulk.len() % 2 != 0 {
+    return Err(RedisError::from((
+      redis::ErrorKind::TypeError,
+      "Invalid length",
+      format!(
+        "Expected even number of key-value elements, but got {}",
+        bulk.len()
+      ),
+    )));
+  }
+  let mut fields = HashMap::with_capacity(bulk.len() / 2);
+  for pair in bulk.chunks_exact(2) {
+    let key = RedisString::from_redis_value(&pair[0])?.0;
+    fields.insert(key, &pair[1]);
+  }
+  Ok(fields)
+}
+
+fn required_field<'a>(fields: &HashMap<String, &'a Value>, name: &str) -> RedisResult<&'a Value> {
+  fields
+    .get(name)
+    .copied()
+    .ok_or_else(|| internal(format!("expecting field `{}`", name)))
+}
+
+/// Max number of characters of a status reply included in [describe_value] output.
+const DESCRIBE_VALUE_MAX_LEN: usize = 32;
+
+/// Returns a short, log-friendly description of a [Value], ie. `Value::Data(12 bytes)`. Payloads
+/// are never dumped in full, as they can be arbitrarily large.
+fn describe_value(v: &Value) -> String {
+  match v {
+    Value::Nil => "Value::Nil".to_string(),
+    Value::Int(i) => format!("Value::Int({})", i),
+    Value::Data(data) => format!("Value::Data({} bytes)", data.len()),
+    Value::Bulk(values) => format!("Value::Bulk({} items)", values.len()),
+    Value::Status(status) => {
+      let mut preview: String = status.chars().take(DESCRIBE_VALUE_MAX_LEN).collect();
+      if preview.len() < status.len() {
+        preview.push_str("...");
+      }
+      format!("Value::Status({:?})", preview)
+    },
+    Value::Okay => "Value::Okay".to_string(),
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CollabControlEvent {
+  Open {
+    workspace_id: String,
+    object_id: String,
+    collab_type: CollabType,
+    #[cfg_attr(feature = "msgpack", serde(with = "serde_bytes"))]
+    doc_state: Vec<u8>,
+  },
+  Close {
+    object_id: String,
+  },
+  /// Carries yrs update inline within the control stream, which saves a separate read of the
+  /// update stream for small sessions.
+  Update {
+    object_id: String,
+    #[cfg_attr(feature = "msgpack", serde(with = "serde_bytes"))]
+    encode_update: Vec<u8>,
+  },
+}
+
+impl Debug for CollabControlEvent {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      #[cfg(feature = "redact")]
+      CollabControlEvent::Open {
+        workspace_id,
+        object_id,
+        collab_type,
+        ..
+      } => f
+        .debug_struct("Open")
+        .field("workspace_id", workspace_id)
+        .field("object_id", object_id)
+        .field("collab_type", collab_type)
+        .field("doc_state", &format_args!("<redacted>"))
+        .finish(),
+      #[cfg(not(feature = "redact"))]
+      CollabControlEvent::Open {
+        workspace_id,
+        object_id,
+        collab_type,
+        doc_state,
+      } => f
+        .debug_struct("Open")
+        .field("workspace_id", workspace_id)
+        .field("object_id", object_id)
+        .field("collab_type", collab_type)
+        .field("doc_state_len", &doc_state.len())
+        .finish(),
+      CollabControlEvent::Close { object_id } => f
+        .debug_struct("Close")
+        .field("object_id", object_id)
+        .finish(),
+      #[cfg(feature = "redact")]
+      CollabControlEvent::Update { object_id, .. } => f
+        .debug_struct("Update")
+        .field("object_id", object_id)
+        .field("encode_update", &format_args!("<redacted>"))
+        .finish(),
+      #[cfg(not(feature = "redact"))]
+      CollabControlEvent::Update {
+        object_id,
+        encode_update,
+      } => f
+        .debug_struct("Update")
+        .field("object_id", object_id)
+        .field("encode_update_len", &encode_update.len())
+        .finish(),
+    }
+  }
+}
+
+impl Display for CollabControlEvent {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CollabControlEvent::Open {
+        workspace_id: _,
+        object_id,
+        collab_type,
+        doc_state: _,
+      } => f.write_fmt(format_args!(
+        "Open collab: object_id:{}|collab_type:{:?}",
+        object_id, collab_type,
+      )),
+      CollabControlEvent::Close { object_id } => {
+        f.write_fmt(format_args!("Close collab: object_id:{}", object_id))
+      },
+      #[cfg(feature = "redact")]
+      CollabControlEvent::Update { object_id, .. } => f.write_fmt(format_args!(
+        "Update collab: object_id:{}|data:<redacted>",
+        object_id
+      )),
+      #[cfg(not(feature = "redact"))]
+      CollabControlEvent::Update {
+        object_id,
+        encode_update,
+      } => f.write_fmt(format_args!(
+        "Update collab: object_id:{}|len:{}",
+        object_id,
+        encode_update.len()
+      )),
+    }
+  }
+}
+
+/// [std::io::Write] sink that only counts bytes written into it.
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0 += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+impl CollabControlEvent {
+  /// Creates an `Open` event. `state` must be the full document state encoded as a v1 update
+  /// against an empty state vector, ie. `txn.encode_state_as_update_v1(&StateVector::default())`,
+  /// so that consumers can always decode it using `Update::decode_v1` and apply it to an empty
+  /// document.
+  pub fn open_from_doc_state<W, O>(
+    workspace_id: W,
+    object_id: O,
+    collab_type: CollabType,
+    state: &[u8],
+  ) -> Self
+  where
+    W: Into<String>,
+    O: Into<String>,
+  {
+    CollabControlEvent::Open {
+      workspace_id: workspace_id.into(),
+      object_id: object_id.into(),
+      collab_type,
+      doc_state: state.to_vec(),
+    }
+  }
+
+  /// Returns id of the collab this event refers to.
+  pub fn object_id(&self) -> &str {
+    match self {
+      CollabControlEvent::Open { object_id, .. }
+      | CollabControlEvent::Close { object_id }
+      | CollabControlEvent::Update { object_id, .. } => object_id,
+    }
+  }
+
+  /// Returns collab type carried by `Open` events. Other events don't carry it.
+  pub fn collab_type(&self) -> Option<CollabType> {
+    match self {
+      CollabControlEvent::Open { collab_type, .. } => Some(collab_type.clone()),
+      CollabControlEvent::Close { .. } | CollabControlEvent::Update { .. } => None,
+    }
+  }
+
+  /// Returns `true` if both events refer to the same logical session, regardless of their
+  /// payloads: `Open` events are compared by `workspace_id`, `object_id` and `collab_type`
+  /// ignoring `doc_state`, while `Close` events are compared by `object_id`. `Update` events carry
+  /// no session information, so they're only the same when fully equal.
+  pub fn same_session(&self, other: &Self) -> bool {
+    match (self, other) {
+      (
+        CollabControlEvent::Open {
+          workspace_id,
+          object_id,
+          collab_type,
+          doc_state: _,
+        },
+        CollabControlEvent::Open {
+          workspace_id: other_workspace_id,
+          object_id: other_object_id,
+          collab_type: other_collab_type,
+          doc_state: _,
+        },
+      ) => {
+        workspace_id == other_workspace_id
+          && object_id == other_object_id
+          && collab_type == other_collab_type
+      },
+      (
+        CollabControlEvent::Close { object_id },
+        CollabControlEvent::Close {
+          object_id: other_object_id,
+        },
+      ) => object_id == other_object_id,
+      (CollabControlEvent::Update { .. }, CollabControlEvent::Update { .. }) => self == other,
+      _ => false,
+    }
+  }
+
+  pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(self)
+  }
+
+  /// Returns length of [CollabControlEvent::encode] output without allocating it.
+  pub fn encoded_len(&self) -> Result<usize, serde_json::Error> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, self)?;
+    Ok(counter.0)
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+    serde_json::from_slice(data)
+  }
+
+  /// Encodes the event as MessagePack, using named fields. Binary fields (like `doc_state`) are
+  /// written as MessagePack `bin`, so unlike JSON (which writes every byte as a decimal number),
+  /// `Open` events take roughly a third of their JSON size.
+  #[cfg(feature = "msgpack")]
+  pub fn encode_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    rmp_serde::to_vec_named(self)
+  }
+
+  #[cfg(feature = "msgpack")]
+  pub fn decode_msgpack(data: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+    rmp_serde::from_slice(data)
+  }
+}
+
+impl TryFrom<CollabControlEvent> for StreamBinary {
+  type Error = StreamError;
+
+  fn try_from(value: CollabControlEvent) -> Result<Self, Self::Error> {
+    let raw_data = value.encode()?;
+    Ok(StreamBinary(raw_data))
+  }
+}
+
+/// Magic number prefixing every zstd frame (`0xFD2FB528` in little endian).
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Prefix byte of [CollabUpdateEvent] encoded as protobuf.
+const EVENT_FORMAT_PROTO: u8 = 0xF0;
+/// Prefix byte of [CollabUpdateEvent] encoded as zstd-compressed protobuf.
+const EVENT_FORMAT_PROTO_ZSTD: u8 = 0xF1;
+
+/// Version of the binary format written by [CollabUpdateEvent::encode] and
+/// [CollabUpdateEvent::encode_compressed]. It follows the format byte as a little endian `u16`.
+/// It must be bumped whenever the encoded layout changes in a way older consumers can't read,
+/// so that they reject such payloads with [StreamError::Unsupported] instead of misreading them.
+pub const WIRE_FORMAT_VERSION: u16 = 1;
+
+/// Protobuf field tag of `update_v1` case of `CollabUpdateEvent.update` oneof.
+const PROTO_UPDATE_V1_TAG: u32 = 1;
+/// Number of leading bytes decompressed by [CollabUpdateEvent::peek_variant]: enough for any
+/// protobuf field key (up to 5 bytes).
+const PEEK_PREFIX_LEN: usize = 8;
+
+/// Variant of an encoded [CollabUpdateEvent], as returned by [CollabUpdateEvent::peek_variant].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateVariant {
+  V1,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CollabUpdateEvent {
+  UpdateV1 { encode_update: Vec<u8> },
+}
+
+impl CollabUpdateEvent {
+  #[allow(dead_code)]
+  fn to_proto(&self) -> proto::collab::CollabUpdateEvent {
+    match self {
+      CollabUpdateEvent::UpdateV1 { encode_update } => proto::collab::CollabUpdateEvent {
+        update: Some(Update::UpdateV1(encode_update.clone())),
+      },
+    }
+  }
+
+  fn from_proto(proto: &proto::collab::CollabUpdateEvent) -> Result<Self, StreamError> {
+    match &proto.update {
+      None => Err(StreamError::UnexpectedValue(
+        "update not set for CollabUpdateEvent proto".to_string(),
+      )),
+      Some(update) => match update {
+        Update::UpdateV1(encode_update) => Ok(CollabUpdateEvent::UpdateV1 {
+          encode_update: encode_update.to_vec(),
+        }),
+      },
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let proto = self.to_proto();
+    let mut buf = Vec::with_capacity(3 + proto.encoded_len());
+    buf.push(EVENT_FORMAT_PROTO);
+    buf.extend_from_slice(&WIRE_FORMAT_VERSION.to_le_bytes());
+    proto
+      .encode(&mut buf)
+      .expect("Vec<u8> provides capacity as needed");
+    buf
+  }
+
+  /// Encodes current event as protobuf, compressed using zstd with a given compression `level`.
+  /// Compressed events are recognized and inflated by [CollabUpdateEvent::decode].
+  pub fn encode_compressed(&self, level: i32) -> Result<Vec<u8>, StreamError> {
+    let proto = self.to_proto().encode_to_vec();
+    let mut buf = vec![EVENT_FORMAT_PROTO_ZSTD];
+    buf.extend_from_slice(&WIRE_FORMAT_VERSION.to_le_bytes());
+    zstd::stream::copy_encode(proto.as_slice(), &mut buf, level)?;
+    Ok(buf)
+  }
+
+  /// Decodes [CollabUpdateEvent] from its binary representation.
+  ///
+  /// Over time, producers used several formats:
+  /// - The earliest versions serialized events using bincode.
+  /// - Later versions wrote plain protobuf messages.
+  /// - Since then, [CollabUpdateEvent::encode] prefixes protobuf with a format byte
+  ///   (`EVENT_FORMAT_PROTO`) and [CollabUpdateEvent::encode_compressed] prefixes zstd-compressed
+  ///   protobuf with `EVENT_FORMAT_PROTO_ZSTD`. Format byte is followed by
+  ///   [WIRE_FORMAT_VERSION], and payloads of newer versions fail with [StreamError::Unsupported].
+  ///
+  /// Prefixed payloads are dispatched deterministically by their first byte. Payloads without
+  /// a known prefix are treated as legacy: they're decoded as protobuf first, falling back to
+  /// bincode. Neither of the legacy formats can start with a format byte: protobuf starts with
+  /// the `update` field tag, while bincode starts with a zero-based variant index.
+  pub fn decode(data: &[u8]) -> Result<Self, StreamError> {
+    Self::decode_any(data, true).inspect_err(|err| Self::log_decode_failure(data, err))
+  }
+
+  /// Same as [CollabUpdateEvent::decode], but accepts protobuf payloads only: bincode payloads
+  /// are rejected as corrupted. Meant for deployments that never had bincode producers, so
+  /// format regressions are caught early.
+  pub fn decode_strict(data: &[u8]) -> Result<Self, StreamError> {
+    Self::decode_any(data, false).inspect_err(|err| Self::log_decode_failure(data, err))
+  }
+
+  fn log_decode_failure(data: &[u8], err: &StreamError) {
+    tracing::warn!(
+      len = data.len(),
+      prefix = ?data.first(),
+      "failed to decode collab update event: {}",
+      err
+    )
+  }
+
+  fn decode_any(data: &[u8], allow_bincode: bool) -> Result<Self, StreamError> {
+    match data.split_first() {
+      Some((&EVENT_FORMAT_PROTO, proto)) => Self::decode_proto(split_wire_version(proto)?),
+      Some((&EVENT_FORMAT_PROTO_ZSTD, compressed)) => {
+        let proto = zstd_decode_bounded(split_wire_version(compressed)?)?;
+        Self::decode_proto(&proto)
+      },
+      _ => Self::decode_legacy(data, allow_bincode),
+    }
+  }
+
+  /// Identifies the variant of an encoded event without decoding (and copying) its payload.
+  /// Recognizes the same formats as [CollabUpdateEvent::decode]. Of compressed events, only the
+  /// leading field key is decompressed.
+  pub fn peek_variant(data: &[u8]) -> Result<UpdateVariant, StreamError> {
+    match data.split_first() {
+      Some((&EVENT_FORMAT_PROTO, proto)) => Self::peek_proto(split_wire_version(proto)?),
+      Some((&EVENT_FORMAT_PROTO_ZSTD, compressed)) => {
+        let compressed = split_wire_version(compressed)?;
+        let mut prefix = Vec::with_capacity(PEEK_PREFIX_LEN);
+        zstd::stream::read::Decoder::new(compressed)?
+          .take(PEEK_PREFIX_LEN as u64)
+          .read_to_end(&mut prefix)
+          .map_err(|err| zstd_error(compressed, err))?;
+        Self::peek_proto_key(&prefix)
+      },
+      _ => Self::peek_legacy(data),
+    }
+  }
+
+  /// Reads only the first field key of protobuf-encoded event. `update` oneof is the only field
+  /// of the message, so its key identifies the variant.
+  fn peek_proto_key(mut data: &[u8]) -> Result<UpdateVariant, StreamError> {
+    use prost::encoding::{decode_key, WireType};
+    let (tag, wire_type) =
+      decode_key(&mut data).map_err(|err| StreamError::UnexpectedValue(err.to_string()))?;
+    match (tag, wire_type) {
+      (PROTO_UPDATE_V1_TAG, WireType::LengthDelimited) => Ok(UpdateVariant::V1),
+      _ => Err(StreamError::UnexpectedValue(format!(
+        "unknown CollabUpdateEvent proto field {}",
+        tag
+      ))),
+    }
+  }
+
+  fn peek_proto(mut data: &[u8]) -> Result<UpdateVariant, StreamError> {
+    use prost::encoding::{decode_key, skip_field, DecodeContext, WireType};
+    let mut variant = None;
+    while !data.is_empty() {
+      let (tag, wire_type) =
+        decode_key(&mut data).map_err(|err| StreamError::UnexpectedValue(err.to_string()))?;
+      if tag == PROTO_UPDATE_V1_TAG && wire_type == WireType::LengthDelimited {
+        variant = Some(UpdateVariant::V1);
+      }
+      skip_field(wire_type, tag, &mut data, DecodeContext::default())
+        .map_err(|err| StreamError::UnexpectedValue(err.to_string()))?;
+    }
+    variant.ok_or_else(|| {
+      StreamError::UnexpectedValue("update not set for CollabUpdateEvent proto".to_string())
+    })
+  }
+
+  fn peek_legacy(data: &[u8]) -> Result<UpdateVariant, StreamError> {
+    Self::peek_proto(data).or_else(|_| {
+      // bincode layout: u32 variant index followed by u64 length-prefixed payload
+      const HEADER_LEN: usize = 12;
+      if data.len() < HEADER_LEN {
+        return Err(StreamError::InvalidFormat);
+      }
+      let variant = u32::from_le_bytes(data[0..4].try_into().unwrap());
+      let len = u64::from_le_bytes(data[4..HEADER_LEN].try_into().unwrap());
+      match variant {
+        0 if len == (data.len() - HEADER_LEN) as u64 => Ok(UpdateVariant::V1),
+        _ => Err(StreamError::InvalidFormat),
+      }
+    })
+  }
+
+  /// Encodes multiple events into a single payload. Each event is encoded using
+  /// [CollabUpdateEvent::encode] and prefixed with its byte length as a little endian `u32`.
+  pub fn encode_batch(events: &[CollabUpdateEvent]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for event in events {
+      let encoded = event.encode();
+      buf.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+      buf.extend_from_slice(&encoded);
+    }
+    buf
+  }
+
+  /// Decodes events encoded using [CollabUpdateEvent::encode_batch]. Returns
+  /// [StreamError::InvalidFormat] if the payload was truncated.
+  pub fn decode_batch(mut data: &[u8]) -> Result<Vec<CollabUpdateEvent>, StreamError> {
+    const LEN_PREFIX: usize = std::mem::size_of::<u32>();
+    let mut events = Vec::new();
+    while !data.is_empty() {
+      if data.len() < LEN_PREFIX {
+        return Err(StreamError::InvalidFormat);
+      }
+      let (len, rest) = data.split_at(LEN_PREFIX);
+      let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+      if rest.len() < len {
+        return Err(StreamError::InvalidFormat);
+      }
+      let (encoded, rest) = rest.split_at(len);
+      events.push(CollabUpdateEvent::decode(encoded)?);
+      data = rest;
+    }
+    Ok(events)
+  }
+
+  fn decode_proto(data: &[u8]) -> Result<Self, StreamError> {
+    let proto = proto::collab::CollabUpdateEvent::decode(data)
+      .map_err(|err| StreamError::UnexpectedValue(err.to_string()))?;
+    CollabUpdateEvent::from_proto(&proto)
+  }
+
+  fn decode_legacy(data: &[u8], allow_bincode: bool) -> Result<Self, StreamError> {
+    if !allow_bincode {
+      return Self::decode_proto(data);
+    }
+    match prost::Message::decode(data) {
+      Ok(proto) => CollabUpdateEvent::from_proto(&proto),
+      Err(_) => match bincode::deserialize(data) {
+        Ok(event) => Ok(event),
+        Err(e) => Err(StreamError::BinCodeSerde(e)),
+      },
+    }
+  }
+}
+
+/// Splits [WIRE_FORMAT_VERSION] from the beginning of `data`, returning the remaining payload.
+fn split_wire_version(data: &[u8]) -> Result<&[u8], StreamError> {
+  if data.len() < 2 {
+    return Err(StreamError::InvalidFormat);
+  }
+  let (version, payload) = data.split_at(2);
+  let version = u16::from_le_bytes([version[0], version[1]]);
+  if version > WIRE_FORMAT_VERSION {
+    return Err(StreamError::Unsupported(format!(
+      "wire format version {} (up to {} is supported)",
+      version, WIRE_FORMAT_VERSION
+    )));
+  }
+  Ok(payload)
+}
+
+impl TryFrom<CollabUpdateEvent> for StreamBinary {
+  type Error = StreamError;
+
+  fn try_from(value: CollabUpdateEvent) -> Result<Self, Self::Error> {
+    let raw_data = value.encode();
+    Ok(StreamBinary(raw_data))
+  }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollabStreamUpdate {
+  pub data: Vec<u8>, // yrs::Update::encode_v1
+  #[serde(with = "collab_origin_serde")]
+  pub sender: CollabOrigin,
+  pub flags: UpdateFlags,
+}
+
+impl CollabStreamUpdate {
+  pub fn new<B, F>(data: B, sender: CollabOrigin, flags: F) -> Self
+  where
+    B: Into<Vec<u8>>,
+    F: Into<UpdateFlags>,
+  {
+    CollabStreamUpdate {
+      data: data.into(),
+      sender,
+      flags: flags.into(),
+    }
+  }
+
+  /// Creates a new [CollabStreamUpdate] carrying the same encoded update as `event`, with flags
+  /// matching the event variant.
+  pub fn from_event(event: CollabUpdateEvent, sender: CollabOrigin) -> Self {
+    match event {
+      CollabUpdateEvent::UpdateV1 { encode_update } => {
+        Self::new(encode_update, sender, UpdateFlags::default())
+      },
+    }
+  }
+
+  /// Returns [CollabUpdateEvent] carrying the same encoded update. Compressed updates are
+  /// decompressed first. Since [CollabUpdateEvent] has no v2 variant yet, v2 encoded updates
+  /// fail with [StreamError::Unsupported].
+  pub fn to_event(&self) -> Result<CollabUpdateEvent, StreamError> {
+    if self.flags.is_v2_encoded() {
+      return Err(StreamError::Unsupported(
+        "CollabUpdateEvent doesn't support v2 encoded updates".to_string(),
+      ));
+    }
+    let encode_update = self
+      .flags
+      .compression()
+      .decompress(&self.data, MAX_DECOMPRESSED_SIZE)?;
+    Ok(CollabUpdateEvent::UpdateV1 { encode_update })
+  }
+
+  /// Creates a new [CollabStreamUpdate] by encoding `update` with v2 encoding if `use_v2` is set,
+  /// or v1 encoding otherwise. Flags always match the encoding used.
+  pub fn from_update(
+    update: &collab::preclude::Update,
+    sender: CollabOrigin,
+    use_v2: bool,
+  ) -> Self {
+    if use_v2 {
+      Self::new_v2(update.encode_v2(), sender)
+    } else {
+      Self::new(update.encode_v1(), sender, UpdateFlags::default())
+    }
+  }
+
+  /// Decodes the update and encodes it again using `target` encoding, ie. to serve v2 updates to
+  /// legacy clients which only understand v1. Compressed updates are compressed again using the
+  /// same algorithm (zstd at [ZstdCompressor::DEFAULT_LEVEL]). If the update already uses `target`
+  /// encoding, it's returned as is.
+  pub fn reencode(self, target: Encoding) -> Result<CollabStreamUpdate, StreamError> {
+    if self.flags.encoding() == target {
+      return Ok(self);
+    }
+    let update =
+      decode_update(&self.data, self.flags).inspect_err(|err| self.log_decode_failure(err))?;
+    let data = match target {
+      Encoding::V1 => update.encode_v1(),
+      Encoding::V2 => update.encode_v2(),
+    };
+    let mut flags = self.flags;
+    flags.set_encoding(target);
+    let data = flags
+      .compression()
+      .compress(&data, ZstdCompressor::DEFAULT_LEVEL)?;
+    Ok(CollabStreamUpdate {
+      data,
+      sender: self.sender,
+      flags,
+    })
+  }
+
+  /// Creates a new [CollabStreamUpdate] flagged with [UpdateFlags::IS_V2_ENCODED]. `data` must
+  /// already be encoded using [EncoderV2](collab::preclude::updates::encoder::EncoderV2). Readers
+  /// pick the decoder based on the flag, so v1 and v2 updates can be mixed in the same stream.
+  pub fn new_v2<B: Into<Vec<u8>>>(data: B, sender: CollabOrigin) -> Self {
+    Self::new(data, sender, UpdateFlags::IS_V2_ENCODED)
+  }
+
+  /// Creates a new [CollabStreamUpdate], eagerly verifying that `data` can be decoded as a yrs
+  /// update (decompressed and decoded using v1 or v2 encoding according to `flags`). This way
+  /// malformed updates are rejected before they ever reach Redis. Empty `data` is rejected with
+  /// [StreamError::InvalidFormat], as it would only produce a useless stream entry.
+  pub fn new_validated<B, F>(data: B, sender: CollabOrigin, flags: F) -> Result<Self, StreamError>
+  where
+    B: Into<Vec<u8>>,
+    F: Into<UpdateFlags>,
+  {
+    let update = Self::new(data, sender, flags);
+    if update.data.is_empty() {
+      return Err(StreamError::InvalidFormat);
+    }
+    decode_update(&update.data, update.flags)?;
+    Ok(update)
+  }
+
+  /// Returns a JSON description of the update for debugging and offline analysis (ie. dumping
+  /// stream contents as NDJSON). This is not a wire format. `data` holds base64 of decompressed
+  /// update bytes (or `null` with an `error` if they can't be decompressed), unless the `redact`
+  /// feature is enabled.
+  pub fn to_debug_json(&self) -> serde_json::Value {
+    let mut json = serde_json::json!({
+      "sender": collab_origin_to_redis_string(&self.sender),
+      "flags": self.flags.to_string(),
+      "data_len": self.data.len(),
+    });
+    #[cfg(feature = "redact")]
+    {
+      json["data"] = "<redacted>".into();
+    }
+    #[cfg(not(feature = "redact"))]
+    {
+      use base64::Engine;
+      match self
+        .flags
+        .compression()
+        .decompress(&self.data, MAX_DECOMPRESSED_SIZE)
+      {
+        Ok(data) => {
+          json["data"] = base64::engine::general_purpose::STANDARD
+            .encode(data)
+            .into()
+        },
+        Err(err) => {
+          json["data"] = serde_json::Value::Null;
+          json["error"] = err.to_string().into();
+        },
+      }
+    }
+    json
+  }
+
+  /// Returns the same update attributed to a different `sender`, ie. when forwarding updates
+  /// between workspaces on behalf of the server. Payload and flags are kept as is.
+  pub fn with_sender(mut self, sender: CollabOrigin) -> Self {
+    self.sender = sender;
+    self
+  }
+
+  /// Returns flags describing encoding and compression of the update payload.
+  #[inline]
+  pub fn flags(&self) -> UpdateFlags {
+    self.flags
+  }
+
+  /// Returns `true` if this update was sent by a client, as opposed to the server or an unknown
+  /// sender. See [is_remote].
+  pub fn is_from_client(&self) -> bool {
+    is_remote(&self.sender)
+  }
+
+  /// Returns device id of the client that sent this update, or `None` if the update was not
+  /// sent by a client.
+  pub fn sender_device_id(&self) -> Option<&str> {
+    origin_device_id(&self.sender)
+  }
+
+  /// Maps the sender into one of `num_partitions` partitions, ie. to shard update processing
+  /// across workers by client. Clients are hashed by their uid and device id with crc32, so the
+  /// mapping is stable across process restarts. Updates from the server or unknown senders always
+  /// land in partition 0.
+  pub fn partition(&self, num_partitions: u32) -> u32 {
+    let num_partitions = num_partitions.max(1);
+    match &self.sender {
+      CollabOrigin::Client(client) => {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&client.uid.to_le_bytes());
+        hasher.update(client.device_id.as_bytes());
+        hasher.finalize() % num_partitions
+      },
+      CollabOrigin::Server | CollabOrigin::Empty => 0,
+    }
+  }
+
+  /// Returns user id of the client that sent this update, or `None` if the update was not sent
+  /// by a client.
+  pub fn sender_uid(&self) -> Option<i64> {
+    origin_uid(&self.sender)
+  }
+
+  /// Returns the same update with its `sender` stored in compact binary form (see
+  /// [encode_origin]) instead of the string form. Readers pick the form from update flags.
+  pub fn with_binary_sender(mut self) -> Self {
+    self.flags.set_binary_sender(true);
+    self
+  }
+
+  /// Returns Redis stream entry fields of this update, always in the same order: `data`, `sender`,
+  /// `flags`. They can be passed directly as `XADD` arguments.
+  pub fn to_fields(&self) -> Vec<(&'static str, Vec<u8>)> {
+    self
+      .to_fields_array()
+      .into_iter()
+      .filter_map(|field| Some((field.name, field.value?.into_owned())))
+      .collect()
+  }
+
+  /// Returns CRC32 checksum of the update `data`.
+  pub fn checksum(&self) -> u32 {
+    crc32fast::hash(&self.data)
+  }
+
+  /// Returns the size of update data as stored in Redis, after compression if any.
+  pub fn compressed_size(&self) -> usize {
+    self.data.len()
+  }
+
+  /// Returns ratio of decompressed to stored update size: the higher it is, the more compression
+  /// pays off. Returns `None` if update is not compressed or cannot be decompressed.
+  pub fn compression_ratio(&self) -> Option<f32> {
+    let compression = self.flags.compression();
+    if compression == Compression::None || self.data.is_empty() {
+      return None;
+    }
+    let decompressed = compression
+      .decompress(&self.data, MAX_DECOMPRESSED_SIZE)
+      .ok()?;
+    Some(decompressed.len() as f32 / self.data.len() as f32)
+  }
+
+  /// Returns `true` if the update is large enough to likely be a full-state snapshot (like the
+  /// `doc_state` of a control `Open` event) rather than an incremental delta: its decompressed
+  /// size is at least `threshold` bytes. Updates which cannot be decompressed return `false`.
+  pub fn looks_like_snapshot(&self, threshold: usize) -> bool {
+    let len = match self.flags.compression() {
+      Compression::None => self.data.len(),
+      compression => match compression.decompress(&self.data, MAX_DECOMPRESSED_SIZE) {
+        Ok(decompressed) => decompressed.len(),
+        Err(_) => return false,
+      },
+    };
+    len >= threshold
+  }
+
+  /// Same as [CollabStreamUpdate::to_fields], but followed by a `checksum` field, which is
+  /// verified when the entry is read back. Entries without the `checksum` field are still
+  /// readable.
+  pub fn to_fields_with_checksum(&self) -> Vec<(&'static str, Vec<u8>)> {
+    let mut fields = self.to_fields();
+    fields.push(("checksum", self.checksum().to_string().into_bytes()));
+    fields
+  }
+
+  /// Returns a builder of [CollabStreamUpdate] for a given update `data`. By default, the update
+  /// has an empty sender and default flags (v1 encoded, uncompressed).
+  pub fn builder<B: Into<Vec<u8>>>(data: B) -> CollabStreamUpdateBuilder {
+    CollabStreamUpdateBuilder {
+      data: data.into(),
+      sender: CollabOrigin::Empty,
+      flags: UpdateFlags::default(),
+      compressor: None,
+    }
+  }
+
+  /// Splits update `data` described by `flags` into chunks of at most `max_chunk` bytes each
+  /// (chunk header included). Every chunk keeps the original `flags` together with
+  /// [UpdateFlags::IS_CHUNK] and is framed with its index and total number of chunks. Chunks must
+  /// be put back together using [CollabStreamUpdate::reassemble] before decoding.
+  ///
+  /// Returns [StreamError::UnexpectedValue] if `max_chunk` is not greater than the chunk header
+  /// size (8 bytes).
+  pub fn chunk<B: AsRef<[u8]>>(
+    data: B,
+    sender: CollabOrigin,
+    flags: UpdateFlags,
+    max_chunk: usize,
+  ) -> Result<Vec<CollabStreamUpdate>, StreamError> {
+    if max_chunk <= CHUNK_HEADER_LEN {
+      return Err(StreamError::UnexpectedValue(format!(
+        "max_chunk must be greater than {} bytes, got {}",
+        CHUNK_HEADER_LEN, max_chunk
+      )));
+    }
+    let mut chunk_flags = flags;
+    chunk_flags.set_chunk(true);
+    let data = data.as_ref();
+    let payloads: Vec<&[u8]> = if data.is_empty() {
+      vec![data]
+    } else {
+      data.chunks(max_chunk - CHUNK_HEADER_LEN).collect()
+    };
+    let total = payloads.len() as u32;
+    let chunks = payloads
+      .into_iter()
+      .enumerate()
+      .map(|(index, payload)| {
+        let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + payload.len());
+        chunk.extend_from_slice(&(index as u32).to_le_bytes());
+        chunk.extend_from_slice(&total.to_le_bytes());
+        chunk.extend_from_slice(payload);
+        CollabStreamUpdate::new(chunk, sender.clone(), chunk_flags)
+      })
+      .collect();
+    Ok(chunks)
+  }
+
+  /// Puts together chunks created by [CollabStreamUpdate::chunk] and decodes the original update
+  /// using the flags the chunks were created with. Chunks must be complete, given in order and
+  /// share the same flags, otherwise [StreamError::InvalidFormat] is returned.
+  pub fn reassemble(
+    chunks: &[CollabStreamUpdate],
+  ) -> Result<collab::preclude::Update, StreamError> {
+    if chunks.is_empty() {
+      return Err(StreamError::InvalidFormat);
+    }
+    let chunk_flags = chunks[0].flags;
+    let mut data = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+      if !chunk.flags.is_chunk()
+        || chunk.flags != chunk_flags
+        || chunk.data.len() < CHUNK_HEADER_LEN
+      {
+        return Err(StreamError::InvalidFormat);
+      }
+      let (header, payload) = chunk.data.split_at(CHUNK_HEADER_LEN);
+      let index = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+      let total = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+      if index as usize != i || total as usize != chunks.len() {
+        return Err(StreamError::InvalidFormat);
+      }
+      data.extend_from_slice(payload);
+    }
+    let mut flags = chunk_flags;
+    flags.set_chunk(false);
+    decode_update(&data, flags)
+  }
+
+  /// Returns Redis stream key, that's storing entries mapped to/from [CollabStreamUpdate].
+  pub fn stream_key(workspace_id: &str, object_id: &str) -> String {
+    // use `:` separator as it adheres to Redis naming conventions
+    format!("af:{}:{}:updates", workspace_id, object_id)
+  }
+
+  /// Same as [CollabStreamUpdate::stream_key], but keeps updates of each [CollabType] in a
+  /// separate stream, ie. so that they can use independent retention policies. Collab type is
+  /// encoded using its numeric value, which is stable across renames.
+  pub fn typed_stream_key(workspace_id: &str, object_id: &str, collab_type: CollabType) -> String {
+    format!(
+      "af:{}:{}:{}:updates",
+      workspace_id,
+      object_id,
+      collab_type.value()
+    )
+  }
+
+  /// Parses a key created by [CollabStreamUpdate::typed_stream_key] back into workspace id,
+  /// object id and collab type. Returns `None` if `key` is not a typed stream key.
+  pub fn parse_typed_stream_key(key: &str) -> Option<(&str, &str, CollabType)> {
+    let key = key.strip_prefix("af:")?.strip_suffix(":updates")?;
+    let mut parts = key.split(':');
+    let (workspace_id, object_id, collab_type) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+      return None;
+    }
+    let collab_type = CollabType::from(collab_type.parse::<i32>().ok()?);
+    Some((workspace_id, object_id, collab_type))
+  }
+
+  pub fn into_update(self) -> Result<collab::preclude::Update, StreamError> {
+    decode_update(&self.data, self.flags).inspect_err(|err| self.log_decode_failure(err))
+  }
+
+  /// Decodes the update and returns its state vector, which can be compared with state vectors
+  /// of other updates to tell if they touch overlapping state.
+  pub fn state_vector(&self) -> Result<StateVector, StreamError> {
+    let update =
+      decode_update(&self.data, self.flags).inspect_err(|err| self.log_decode_failure(err))?;
+    Ok(update.state_vector())
+  }
+
+  /// Decodes the update and checks if it carries no changes at all: neither inserted blocks nor
+  /// deletions. Such updates can be skipped without applying them. Malformed updates return
+  /// decode error instead.
+  pub fn is_empty_update(&self) -> Result<bool, StreamError> {
+    let update =
+      decode_update(&self.data, self.flags).inspect_err(|err| self.log_decode_failure(err))?;
+    Ok(update.state_vector().is_empty() && update.delete_set().is_empty())
+  }
+
+  /// Decodes the update without consuming it. If the update is compressed, it's decompressed
+  /// into `scratch`, which is cleared first. Reusing the same `scratch` buffer across calls
+  /// avoids allocating a new buffer for every decompressed update.
+  pub fn decode_into(
+    &self,
+    scratch: &mut Vec<u8>,
+  ) -> Result<collab::preclude::Update, StreamError> {
+    decode_update_with(&self.data, self.flags, MAX_DECOMPRESSED_SIZE, scratch)
+      .inspect_err(|err| self.log_decode_failure(err))
+  }
+
+  /// Same as [CollabStreamUpdate::into_update], but fails with [StreamError::TooLarge] if
+  /// compressed update would decompress to more than `max_decompressed_size` bytes.
+  pub fn into_update_with_limit(
+    self,
+    max_decompressed_size: usize,
+  ) -> Result<collab::preclude::Update, StreamError> {
+    decode_update_bounded(&self.data, self.flags, max_decompressed_size)
+      .inspect_err(|err| self.log_decode_failure(err))
+  }
+
+  /// Same as [CollabStreamUpdate::into_update], but decompresses the payload using `compressor`
+  /// instead of the built-in codec of the [Compression] kind recorded in flags. Updates built
+  /// with a custom codec (see [CollabStreamUpdateBuilder::compressed_with]) must be read back this
+  /// way. Fails with [StreamError::Unsupported] if `compressor` is of a different kind.
+  pub fn into_update_with(
+    self,
+    compressor: &dyn Compressor,
+  ) -> Result<collab::preclude::Update, StreamError> {
+    decode_update_custom(&self.data, self.flags, compressor)
+      .inspect_err(|err| self.log_decode_failure(err))
+  }
+
+  fn log_decode_failure(&self, err: &StreamError) {
+    tracing::warn!(
+      sender = %self.sender,
+      flags = %self.flags,
+      len = self.data.len(),
+      "failed to decode collab stream update: {}",
+      err
+    );
+  }
+}
+
+/// Default upper bound of a decompressed [CollabStreamUpdate] payload size (64 MiB). Stream
+/// entries may come from semi-trusted clients, so decompression must never be unbounded.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the header prepended to every chunk created by [CollabStreamUpdate::chunk]: chunk index
+/// followed by total number of chunks, both as u32 little endian.
+const CHUNK_HEADER_LEN: usize = 8;
+
+/// Decodes yrs update from borrowed `data`, decompressing and choosing the decoder according to
+/// `flags`. Useful when the payload is already held elsewhere, as it doesn't require copying it
+/// into a [CollabStreamUpdate] first. Compressed payloads are limited to
+/// [MAX_DECOMPRESSED_SIZE].
+pub fn decode_update(
+  data: &[u8],
+  flags: UpdateFlags,
+) -> Result<collab::preclude::Update, StreamError> {
+  decode_update_bounded(data, flags, MAX_DECOMPRESSED_SIZE)
+}
+
+/// Same as [decode_update], but with custom limit of decompressed payload size.
+fn decode_update_bounded(
+  data: &[u8],
+  flags: UpdateFlags,
+  max_decompressed_size: usize,
+) -> Result<collab::preclude::Update, StreamError> {
+  let mut scratch = Vec::new();
+  decode_update_with(data, flags, max_decompressed_size, &mut scratch)
+}
+
+/// Same as [decode_update_bounded], but uses `scratch` buffer for decompression.
+fn decode_update_with(
+  data: &[u8],
+  flags: UpdateFlags,
+  max_decompressed_size: usize,
+  scratch: &mut Vec<u8>,
+) -> Result<collab::preclude::Update, StreamError> {
+  let result = decode_update_flagged(data, flags, max_decompressed_size, scratch);
+  #[cfg(feature = "metrics")]
+  crate::metrics::record_decode(flags, result.is_ok());
+  result
+}
+
+fn decode_update_flagged(
+  data: &[u8],
+  flags: UpdateFlags,
+  max_decompressed_size: usize,
+  scratch: &mut Vec<u8>,
+) -> Result<collab::preclude::Update, StreamError> {
+  if flags.is_chunk() {
+    return Err(StreamError::Unsupported(
+      "update chunk must be reassembled before decoding".to_string(),
+    ));
+  }
+  let bytes = match flags.compression() {
+    Compression::None => data,
+    compression => {
+      compression.decompress_into(data, max_decompressed_size, scratch)?;
+      scratch.as_slice()
+    },
+  };
+  flags.encoding().decode(bytes)
+}
+
+/// Same as [decode_update_flagged], but decompresses using a given codec.
+fn decode_update_custom(
+  data: &[u8],
+  flags: UpdateFlags,
+  compressor: &dyn Compressor,
+) -> Result<collab::preclude::Update, StreamError> {
+  if flags.is_chunk() {
+    return Err(StreamError::Unsupported(
+      "update chunk must be reassembled before decoding".to_string(),
+    ));
+  }
+  match flags.compression() {
+    Compression::None => flags.encoding().decode(data),
+    compression if compression == compressor.compression() => {
+      let bytes = compressor.decompress(data, MAX_DECOMPRESSED_SIZE)?;
+      flags.encoding().decode(&bytes)
+    },
+    compression => Err(StreamError::Unsupported(format!(
+      "update compressed as {:?} can't be decompressed by {:?} codec",
+      compression,
+      compressor.compression()
+    ))),
+  }
+}
+
+impl Encoding {
+  /// Decodes yrs update from `bytes` using this encoding.
+  pub fn decode(&self, bytes: &[u8]) -> Result<collab::preclude::Update, StreamError> {
+    let update = match self {
+      Encoding::V1 => collab::preclude::Update::decode_v1(bytes)?,
+      Encoding::V2 => collab::preclude::Update::decode_v2(bytes)?,
+    };
+    Ok(update)
+  }
+}
+
+impl Compression {
+  /// Compresses `data`. `level` is only used by [Compression::Zstd].
+  pub fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>, StreamError> {
+    match self {
+      Compression::None => Ok(data.to_vec()),
+      // single-shot compression records decompressed size in the frame header, which lets
+      // decoder pick chunked decompression for large payloads
+      Compression::Zstd => Ok(zstd::bulk::compress(data, level)?),
+      #[cfg(feature = "lz4")]
+      Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+      #[cfg(not(feature = "lz4"))]
+      Compression::Lz4 => Err(StreamError::Unsupported(
+        "lz4 compression requires `lz4` feature".to_string(),
+      )),
+    }
+  }
+
+  /// Decompresses `data`, failing with [StreamError::TooLarge] as soon as decompressed output
+  /// would exceed `limit` bytes.
+  pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, StreamError> {
+    let mut buf = Vec::new();
+    self.decompress_into(data, limit, &mut buf)?;
+    Ok(buf)
+  }
+
+  /// Same as [Compression::decompress], but writes decompressed output into `buf`. `buf` is
+  /// cleared first, but its capacity is reused.
+  pub fn decompress_into(
+    &self,
+    data: &[u8],
+    limit: usize,
+    buf: &mut Vec<u8>,
+  ) -> Result<(), StreamError> {
+    buf.clear();
+    match self {
+      Compression::None => {
+        buf.extend_from_slice(data);
+        Ok(())
+      },
+      Compression::Zstd => zstd_decompress_bounded(data, limit, buf),
+      #[cfg(feature = "lz4")]
+      Compression::Lz4 => lz4_decompress_bounded(data, limit, buf),
+      #[cfg(not(feature = "lz4"))]
+      Compression::Lz4 => Err(StreamError::Unsupported(
+        "lz4 decompression requires `lz4` feature".to_string(),
+      )),
+    }
+  }
+}
+
+/// Decompressed size (as declared by zstd frame header) starting from which payloads use chunked
+/// decompression: the output is read in fixed-size chunks into a buffer which grows as output
+/// arrives, but never past the declared size, instead of relying on [Read::read_to_end] growing
+/// its buffer by doubling. This keeps peak memory of very large updates close to their actual
+/// size, while a forged header can't make us allocate more than the output really takes. It's
+/// not streaming: yrs decoders only work over contiguous slices, so the whole decompressed
+/// payload is still materialized before decoding.
+pub const CHUNKED_DECOMPRESSION_THRESHOLD: usize = 1024 * 1024;
+
+/// Size of a single chunk read from zstd decoder by chunked decompression.
+const CHUNKED_DECOMPRESSION_CHUNK: usize = 64 * 1024;
+
+/// Decompresses a zstd frame of an event payload, failing with [StreamError::TooLarge] once the
+/// output exceeds [MAX_DECOMPRESSED_SIZE], so that tiny crafted payloads can't exhaust memory.
+fn zstd_decode_bounded(data: &[u8]) -> Result<Vec<u8>, StreamError> {
+  let mut buf = Vec::new();
+  zstd_decompress_bounded(data, MAX_DECOMPRESSED_SIZE, &mut buf)?;
+  Ok(buf)
+}
+
+/// Classifies zstd decoding error: payloads that don't start with zstd magic are not zstd at all
+/// ([StreamError::InvalidFormat]), while frames ending prematurely were truncated
+/// ([StreamError::Truncated]), so re-fetching them may help.
+fn zstd_error(data: &[u8], err: std::io::Error) -> StreamError {
+  if !data.starts_with(&ZSTD_MAGIC) {
+    if ZSTD_MAGIC.starts_with(data) {
+      // not even the whole magic number made it through
+      return StreamError::Truncated;
+    }
+    StreamError::InvalidFormat
+  } else if err.kind() == std::io::ErrorKind::UnexpectedEof {
+    StreamError::Truncated
+  } else {
+    StreamError::IO(err)
+  }
+}
+
+fn zstd_decompress_bounded(
+  data: &[u8],
+  limit: usize,
+  buf: &mut Vec<u8>,
+) -> Result<(), StreamError> {
+  zstd_decompress_bounded_inner(data, limit, buf).map_err(|err| match err {
+    StreamError::IO(err) => zstd_error(data, err),
+    err => err,
+  })
+}
+
+fn zstd_decompress_bounded_inner(
+  data: &[u8],
+  limit: usize,
+  buf: &mut Vec<u8>,
+) -> Result<(), StreamError> {
+  let decoder = zstd::stream::read::Decoder::new(data)?;
+  // content size is optional and comes from untrusted input, so it's only used as a hint
+  let declared = zstd::zstd_safe::get_frame_content_size(data)
+    .ok()
+    .flatten()
+    .map(|size| size.min(limit as u64 + 1) as usize);
+  match declared {
+    Some(size) if size >= CHUNKED_DECOMPRESSION_THRESHOLD => {
+      zstd_decompress_chunked(decoder, size, limit, buf)
+    },
+    _ => {
+      // read at most one byte over the limit - just enough to tell if the limit was exceeded
+      decoder.take(limit as u64 + 1).read_to_end(buf)?;
+      if buf.len() > limit {
+        return Err(StreamError::TooLarge(limit));
+      }
+      Ok(())
+    },
+  }
+}
+
+fn zstd_decompress_chunked<R: Read>(
+  mut decoder: R,
+  declared_size: usize,
+  limit: usize,
+  buf: &mut Vec<u8>,
+) -> Result<(), StreamError> {
+  let mut chunk = [0u8; CHUNKED_DECOMPRESSION_CHUNK];
+  loop {
+    let n = match decoder.read(&mut chunk) {
+      Ok(0) => return Ok(()),
+      Ok(n) => n,
+      Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err.into()),
+    };
+    if buf.len() + n > limit {
+      return Err(StreamError::TooLarge(limit));
+    }
+    if buf.capacity() - buf.len() < n {
+      // grow geometrically, but don't overshoot the declared size
+      let additional = buf
+        .len()
+        .max(CHUNKED_DECOMPRESSION_CHUNK)
+        .min(declared_size.saturating_sub(buf.len()))
+        .max(n);
+      buf.reserve_exact(additional);
+    }
+    buf.extend_from_slice(&chunk[..n]);
+  }
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_decompress_bounded(data: &[u8], limit: usize, buf: &mut Vec<u8>) -> Result<(), StreamError> {
+  // lz4 payload is prefixed with its decompressed size as little endian u32
+  if data.len() < 4 {
+    return Err(StreamError::InvalidFormat);
+  }
+  let (size, compressed) = data.split_at(4);
+  let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+  if size > limit {
+    return Err(StreamError::TooLarge(limit));
+  }
+  buf.resize(size, 0);
+  let written = lz4_flex::decompress_into(compressed, buf)
+    .map_err(|err| StreamError::UnexpectedValue(err.to_string()))?;
+  buf.truncate(written);
+  Ok(())
+}
+
+/// Compression codec of [CollabStreamUpdate] payloads. Codec identifies itself with one of
+/// [Compression] kinds, which is recorded in [UpdateFlags] of compressed updates. Readers like
+/// [CollabStreamUpdate::into_update] decompress using the built-in codec of the recorded kind,
+/// so updates compressed by custom codecs must be read with [CollabStreamUpdate::into_update_with].
+pub trait Compressor: Send + Sync {
+  /// Kind of compression recorded in flags of updates compressed by this codec.
+  fn compression(&self) -> Compression;
+
+  fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError>;
+
+  /// Decompresses `data`, failing with [StreamError::TooLarge] if the output would exceed
+  /// `limit` bytes.
+  fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, StreamError>;
+}
+
+/// Default [Compressor] using zstd.
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCompressor {
+  level: i32,
+}
+
+impl ZstdCompressor {
+  pub const DEFAULT_LEVEL: i32 = 3;
+
+  pub fn new(level: i32) -> Self {
+    ZstdCompressor { level }
+  }
+}
+
+impl Default for ZstdCompressor {
+  fn default() -> Self {
+    Self::new(Self::DEFAULT_LEVEL)
+  }
+}
+
+impl Compressor for ZstdCompressor {
+  fn compression(&self) -> Compression {
+    Compression::Zstd
+  }
+
+  fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError> {
+    Compression::Zstd.compress(data, self.level)
+  }
+
+  fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, StreamError> {
+    Compression::Zstd.decompress(data, limit)
+  }
+}
+
+/// [Compressor] using lz4.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Compressor;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4Compressor {
+  fn compression(&self) -> Compression {
+    Compression::Lz4
+  }
+
+  fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError> {
+    Compression::Lz4.compress(data, 0)
+  }
+
+  fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, StreamError> {
+    Compression::Lz4.decompress(data, limit)
+  }
+}
+
+/// Builder of [CollabStreamUpdate], created by [CollabStreamUpdate::builder].
+pub struct CollabStreamUpdateBuilder {
+  data: Vec<u8>,
+  sender: CollabOrigin,
+  flags: UpdateFlags,
+  compressor: Option<Box<dyn Compressor>>,
+}
+
+impl CollabStreamUpdateBuilder {
+  pub fn sender(mut self, sender: CollabOrigin) -> Self {
+    self.sender = sender;
+    self
+  }
+
+  pub fn from_server(self) -> Self {
+    self.sender(CollabOrigin::Server)
+  }
+
+  pub fn flags<F: Into<UpdateFlags>>(mut self, flags: F) -> Self {
+    self.flags = flags.into();
+    self
+  }
+
+  /// Compresses update data using zstd with a given compression `level` when building.
+  /// [UpdateFlags::IS_COMPRESSED] flag is set accordingly.
+  pub fn compressed(self, level: i32) -> Self {
+    self.compressed_with(Box::new(ZstdCompressor::new(level)))
+  }
+
+  /// Compresses update data using lz4 when building. [UpdateFlags::IS_COMPRESSED] and
+  /// [UpdateFlags::IS_LZ4_COMPRESSED] flags are set accordingly.
+  #[cfg(feature = "lz4")]
+  pub fn compressed_lz4(self) -> Self {
+    self.compressed_with(Box::new(Lz4Compressor))
+  }
+
+  /// Compresses update data using a given `compressor` when building. Flags are set according to
+  /// [Compressor::compression]. Unless `compressor` is compatible with the built-in codec of its
+  /// kind, the update must be read with [CollabStreamUpdate::into_update_with].
+  pub fn compressed_with(mut self, compressor: Box<dyn Compressor>) -> Self {
+    self.compressor = Some(compressor);
+    self
+  }
+
+  pub fn build(self) -> Result<CollabStreamUpdate, StreamError> {
+    let mut flags = self.flags;
+    let data = match self.compressor {
+      Some(compressor) => {
+        flags.set_compression(compressor.compression());
+        compressor.compress(&self.data)?
+      },
+      None => self.data,
+    };
+    Ok(CollabStreamUpdate {
+      data,
+      sender: self.sender,
+      flags,
+    })
+  }
+}
+
+impl Debug for CollabStreamUpdate {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut s = f.debug_struct("CollabStreamUpdate");
+    s.field("sender", &self.sender)
+      .field("flags", &format_args!("{}", self.flags));
+    #[cfg(feature = "redact")]
+    s.field("data", &format_args!("<redacted>"));
+    #[cfg(not(feature = "redact"))]
+    s.field("data_len", &self.data.len());
+    s.finish()
+  }
+}
+
+impl Display for CollabStreamUpdate {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    #[cfg(feature = "redact")]
+    return f.write_fmt(format_args!(
+      "Collab update: sender:[{}]|flags:{}|data:<redacted>",
+      collab_origin_to_redis_string(&self.sender),
+      self.flags,
+    ));
+    #[cfg(not(feature = "redact"))]
+    f.write_fmt(format_args!(
+      "Collab update: sender:[{}]|flags:{}|len:{}",
+      collab_origin_to_redis_string(&self.sender),
+      self.flags,
+      self.data.len()
+    ))
+  }
+}
+
+impl TryFrom<HashMap<String, redis::Value>> for CollabStreamUpdate {
+  type Error = StreamError;
+
+  fn try_from(fields: HashMap<String, Value>) -> Result<Self, Self::Error> {
+    let flags = match fields.get("flags") {
+      None => UpdateFlags::default(),
+      Some(flags) => u8::from_redis_value(flags).unwrap_or(0).into(),
+    };
+    let sender = match fields.get("sender") {
+      None => CollabOrigin::Empty,
+      Some(sender) => parse_sender(&Vec::<u8>::from_redis_value(sender)?, flags)?,
+    };
+    let data_raw = fields
+      .get("data")
+      .ok_or_else(|| internal("expecting field `data`"))?;
+    let data: Vec<u8> = FromRedisValue::from_redis_value(data_raw)?;
+    if let Some(checksum) = fields.get("checksum") {
+      verify_checksum(&data, u32::from_redis_value(checksum)?)?;
+    }
+    Ok(CollabStreamUpdate {
+      data,
+      sender,
+      flags,
+    })
+  }
+}
+
+impl CollabStreamUpdate {
+  /// Transport-agnostic counterpart of `TryFrom<HashMap<String, redis::Value>>`: reads `data`,
+  /// `sender`, `flags` and optional `checksum` fields from byte-valued message headers, ie. as
+  /// delivered by message brokers other than Redis. Field values are expected in the same form
+  /// as produced by [CollabStreamUpdate::to_fields_with_checksum].
+  pub fn from_headers(headers: &HashMap<String, Vec<u8>>) -> Result<Self, StreamError> {
+    let flags = match headers.get("flags") {
+      None => UpdateFlags::default(),
+      Some(flags) => std::str::from_utf8(flags)
+        .ok()
+        .and_then(|flags| flags.parse::<u8>().ok())
+        .unwrap_or(0)
+        .into(),
+    };
+    let sender = match headers.get("sender") {
+      None => CollabOrigin::Empty,
+      Some(sender) => parse_sender(sender, flags)?,
+    };
+    let data = headers
+      .get("data")
+      .ok_or_else(|| StreamError::UnexpectedValue("expecting field `data`".to_string()))?
+      .clone();
+    if let Some(checksum) = headers.get("checksum") {
+      verify_checksum(&data, std::str::from_utf8(checksum)?.parse()?)?;
+    }
+    Ok(CollabStreamUpdate {
+      data,
+      sender,
+      flags,
+    })
+  }
+}
+
+fn verify_checksum(data: &[u8], expected: u32) -> Result<(), StreamError> {
+  let actual = crc32fast::hash(data);
+  if expected != actual {
+    return Err(StreamError::ChecksumMismatch { expected, actual });
+  }
+  Ok(())
+}
+
+pub struct AwarenessStreamUpdate {
+  pub data: Vec<u8>, // AwarenessUpdate::encode_v1
+  pub sender: CollabOrigin,
+  /// Unix timestamp (in milliseconds) after which the entry can be trimmed, stored in the
+  /// `expires_at` field. `None` means the entry never expires.
+  pub expires_at: Option<u64>,
+}
+
+impl AwarenessStreamUpdate {
+  /// Returns Redis stream key, that's storing entries mapped to/from [AwarenessStreamUpdate].
+  pub fn stream_key(workspace_id: &str, object_id: &str) -> String {
+    format!("af:{}:{}:awareness", workspace_id, object_id)
+  }
+
+  /// Creates a new [AwarenessStreamUpdate] from awareness `update`, encoded using v1 encoding.
+  pub fn from_awareness(update: &AwarenessUpdate, sender: CollabOrigin) -> Self {
+    AwarenessStreamUpdate {
+      data: update.encode_v1(),
+      sender,
+      expires_at: None,
+    }
+  }
+
+  /// Returns the same update attributed to a different `sender`. Payload and expiry time are
+  /// kept as is.
+  pub fn with_sender(mut self, sender: CollabOrigin) -> Self {
+    self.sender = sender;
+    self
+  }
+
+  /// Decodes awareness update carried by this entry.
+  pub fn into_awareness_update(self) -> Result<AwarenessUpdate, StreamError> {
+    Ok(AwarenessUpdate::decode_v1(&self.data)?)
+  }
+
+  /// Keeps only the last update of each client, ie. to catch up on awareness state without
+  /// replaying every cursor move. Coalesced updates are ordered by the first appearance of their
+  /// clients in `updates`. Updates from [CollabOrigin::Server] and [CollabOrigin::Empty] are not
+  /// tied to a single client, so all of them are kept in place.
+  pub fn coalesce(updates: Vec<AwarenessStreamUpdate>) -> Vec<AwarenessStreamUpdate> {
+    let mut result: Vec<AwarenessStreamUpdate> = Vec::with_capacity(updates.len());
+    let mut slots: HashMap<(i64, String), usize> = HashMap::new();
+    for update in updates {
+      let client = match &update.sender {
+        CollabOrigin::Client(client) => Some((client.uid, client.device_id.clone())),
+        CollabOrigin::Server | CollabOrigin::Empty => None,
+      };
+      match client {
+        Some(client) => match slots.entry(client) {
+          Entry::Occupied(slot) => result[*slot.get()] = update,
+          Entry::Vacant(slot) => {
+            slot.insert(result.len());
+            result.push(update);
+          },
+        },
+        None => result.push(update),
+      }
+    }
+    result
+  }
+
+  /// Returns `true` if the entry has an expiry time which is at or before `now`. Entries
+  /// without expiry time never expire.
+  pub fn is_expired(&self, now: SystemTime) -> bool {
+    match self.expires_at {
+      None => false,
+      Some(expires_at) => now >= UNIX_EPOCH + Duration::from_millis(expires_at),
+    }
+  }
+
+  /// Returns device id of the client that sent this update, or `None` if the update was not
+  /// sent by a client.
+  pub fn sender_device_id(&self) -> Option<&str> {
+    origin_device_id(&self.sender)
+  }
+
+  /// Returns user id of the client that sent this update, or `None` if the update was not sent
+  /// by a client.
+  pub fn sender_uid(&self) -> Option<i64> {
+    origin_uid(&self.sender)
+  }
+}
+
+/// Identifies a collab by workspace and object id. Implementors get [StreamKeyed] for free.
+pub trait CollabObjectId {
+  fn workspace_id(&self) -> &str;
+  fn object_id(&self) -> &str;
+}
+
+/// Redis stream keys of a collab, so that call sites don't have to pull workspace and object
+/// ids out by hand.
+pub trait StreamKeyed {
+  /// Key of the stream storing [CollabStreamUpdate]s, see [CollabStreamUpdate::stream_key].
+  fn updates_key(&self) -> String;
+  /// Key of the stream storing [AwarenessStreamUpdate]s, see [AwarenessStreamUpdate::stream_key].
+  fn awareness_key(&self) -> String;
+}
+
+impl<T: CollabObjectId + ?Sized> StreamKeyed for T {
+  fn updates_key(&self) -> String {
+    CollabStreamUpdate::stream_key(self.workspace_id(), self.object_id())
+  }
+
+  fn awareness_key(&self) -> String {
+    AwarenessStreamUpdate::stream_key(self.workspace_id(), self.object_id())
+  }
+}
+
+/// Single field of a Redis stream entry. Payloads are borrowed rather than copied. Fields without
+/// a value are skipped when written as redis args, so optional fields keep their slot in
+/// [ToFieldsArray] arrays.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryField<'a> {
+  pub name: &'static str,
+  pub value: Option<Cow<'a, [u8]>>,
+}
+
+impl<'a> EntryField<'a> {
+  pub fn borrowed(name: &'static str, value: &'a [u8]) -> Self {
+    EntryField {
+      name,
+      value: Some(Cow::Borrowed(value)),
+    }
+  }
+
+  pub fn owned(name: &'static str, value: Vec<u8>) -> Self {
+    EntryField {
+      name,
+      value: Some(Cow::Owned(value)),
+    }
+  }
+}
+
+impl ToRedisArgs for EntryField<'_> {
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    if let Some(value) = &self.value {
+      out.write_arg(self.name.as_bytes());
+      out.write_arg(value);
+    }
+  }
+
+  fn is_single_arg(&self) -> bool {
+    false
+  }
+}
+
+/// Redis stream entry fields known upfront to be at most `N`, so that they can be passed as `XADD`
+/// arguments without allocating a `Vec` for the field list or copying the payload (like
+/// [StreamBinary::into_tuple_array]). Only small metadata fields are formatted into new buffers.
+pub trait ToFieldsArray<const N: usize> {
+  fn to_fields_array(&self) -> [EntryField<'_>; N];
+}
+
+/// Same fields and order as [CollabStreamUpdate::to_fields]: `data`, `sender`, `flags`.
+impl ToFieldsArray<3> for CollabStreamUpdate {
+  fn to_fields_array(&self) -> [EntryField<'_>; 3] {
+    let sender = if self.flags.has_binary_sender() {
+      encode_origin(&self.sender)
+    } else {
+      collab_origin_to_redis_string(&self.sender).into_bytes()
+    };
+    [
+      EntryField::borrowed("data", &self.data),
+      EntryField::owned("sender", sender),
+      EntryField::owned("flags", u8::from(self.flags).to_string().into_bytes()),
+    ]
+  }
+}
+
+/// `data`, `sender` and `expires_at` fields, the latter without a value if not set.
+impl ToFieldsArray<3> for AwarenessStreamUpdate {
+  fn to_fields_array(&self) -> [EntryField<'_>; 3] {
+    [
+      EntryField::borrowed("data", &self.data),
+      EntryField::owned(
+        "sender",
+        collab_origin_to_redis_string(&self.sender).into_bytes(),
+      ),
+      EntryField {
+        name: "expires_at",
+        value: self
+          .expires_at
+          .map(|expires_at| Cow::Owned(expires_at.to_string().into_bytes())),
+      },
+    ]
+  }
+}
+
+impl Debug for AwarenessStreamUpdate {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut s = f.debug_struct("AwarenessStreamUpdate");
+    s.field("sender", &self.sender);
+    #[cfg(feature = "redact")]
+    s.field("data", &format_args!("<redacted>"));
+    #[cfg(not(feature = "redact"))]
+    s.field("data_len", &self.data.len());
+    s.finish()
+  }
+}
+
+impl TryFrom<HashMap<String, redis::Value>> for AwarenessStreamUpdate {
+  type Error = StreamError;
+
+  fn try_from(fields: HashMap<String, Value>) -> Result<Self, Self::Error> {
+    let sender = match fields.get("sender") {
+      None => CollabOrigin::Empty,
+      Some(sender) => {
+        let raw_origin = String::from_redis_value(sender)?;
+        collab_origin_from_str(&raw_origin)?
+      },
+    };
+    let data_raw = fields
+      .get("data")
+      .ok_or_else(|| internal("expecting field `data`"))?;
+    let data: Vec<u8> = FromRedisValue::from_redis_value(data_raw)?;
+    let expires_at = match fields.get("expires_at") {
+      None => None,
+      Some(expires_at) => Some(u64::from_redis_value(expires_at)?),
+    };
+    Ok(AwarenessStreamUpdate {
+      data,
+      sender,
+      expires_at,
+    })
+  }
+}
+
+fn origin_device_id(origin: &CollabOrigin) -> Option<&str> {
+  match origin {
+    CollabOrigin::Client(client) => Some(&client.device_id),
+    CollabOrigin::Server | CollabOrigin::Empty => None,
+  }
+}
+
+fn origin_uid(origin: &CollabOrigin) -> Option<i64> {
+  match origin {
+    CollabOrigin::Client(client) => Some(client.uid),
+    CollabOrigin::Server | CollabOrigin::Empty => None,
+  }
+}
+
+/// Kinds of streams kept per collab, used as the last segment of their keys.
+const STREAM_KEY_KINDS: [&str; 3] = ["updates", "awareness", "control"];
+
+/// Returns `true` if `key` has the `af:{workspace_id}:{object_id}:{kind}` shape of collab
+/// stream keys. See [validate_stream_key].
+pub fn is_valid_stream_key(key: &str) -> bool {
+  validate_stream_key(key).is_ok()
+}
+
+/// Checks that `key` has the `af:{workspace_id}:{object_id}:{kind}` shape, where both ids are
+/// non-empty and `kind` is one of `updates`, `awareness` or `control`. Keys created by
+/// [CollabStreamUpdate::typed_stream_key] are accepted as well. Useful for auditing a keyspace
+/// for keys created by hand.
+pub fn validate_stream_key(key: &str) -> Result<(), StreamError> {
+  let invalid = |reason: &str| {
+    Err(StreamError::UnexpectedValue(format!(
+      "invalid stream key `{}`: {}",
+      key, reason
+    )))
+  };
+  let Some(rest) = key.strip_prefix("af:") else {
+    return invalid("missing `af:` prefix");
+  };
+  if CollabStreamUpdate::parse_typed_stream_key(key)
+    .is_some_and(|(workspace_id, object_id, _)| !workspace_id.is_empty() && !object_id.is_empty())
+  {
+    return Ok(());
+  }
+  let segments: Vec<&str> = rest.split(':').collect();
+  let [workspace_id, object_id, kind] = segments.as_slice() else {
+    return invalid("expecting `af:{workspace_id}:{object_id}:{kind}`");
+  };
+  if workspace_id.is_empty() || object_id.is_empty() {
+    return invalid("empty workspace or object id");
+  }
+  if !STREAM_KEY_KINDS.contains(kind) {
+    return invalid("unknown stream kind");
+  }
+  Ok(())
+}
+
+/// Returns `true` only for [CollabOrigin::Client], ie. when an update came from a real client and
+/// should be broadcast to other clients.
+pub fn is_remote(origin: &CollabOrigin) -> bool {
+  matches!(origin, CollabOrigin::Client(_))
+}
+
+/// Returns the string form of [CollabOrigin] stored in the `sender` field of Redis stream entries.
+/// It's guaranteed to be parsed back by [collab_origin_from_str] into the same origin.
+pub fn collab_origin_to_redis_string(origin: &CollabOrigin) -> String {
+  match origin {
+    CollabOrigin::Empty => String::new(),
+    CollabOrigin::Server => "server".to_string(),
+    CollabOrigin::Client(client) => format!("uid:{}|device_id:{}", client.uid, client.device_id),
+  }
+}
+
+//FIXME: this should be `impl FromStr for CollabOrigin`
+/// Parses [CollabOrigin] from the `sender` field of Redis stream entries. This is an inverse of
+/// [collab_origin_to_redis_string].
+pub fn collab_origin_from_str(value: &str) -> RedisResult<CollabOrigin> {
+  match value {
+    "" => Ok(CollabOrigin::Empty),
+    "server" => Ok(CollabOrigin::Server),
+    other => {
+      // canonical form is `uid:{uid}|device_id:{device_id}`: device id is the last segment, so
+      // it can contain `|` itself. Legacy `device_id:{device_id}|uid:{uid}` form is accepted too.
+      // In either form exactly one segment must be `uid:` and the other `device_id:`.
+      let parts = match other.strip_prefix("uid:") {
+        Some(rest) => rest
+          .split_once('|')
+          .and_then(|(uid, device_id)| Some((uid, device_id.strip_prefix("device_id:")?))),
+        None => other
+          .strip_prefix("device_id:")
+          .and_then(|rest| rest.rsplit_once('|'))
+          .and_then(|(device_id, uid)| Some((uid.strip_prefix("uid:")?, device_id))),
+      };
+      match parts {
+        Some((uid, device_id)) => {
+          let uid: i64 = uid
+            .parse()
+            .map_err(|err| internal(format!("failed to parse uid: {}", err)))?;
+          Ok(CollabOrigin::Client(CollabClient {
+            uid,
+            device_id: device_id.to_string(),
+          }))
+        },
+        None => Err(internal(format!(
+          "couldn't parse collab origin from `{}`",
+          other
+        ))),
+      }
+    },
+  }
+}
+
+const ORIGIN_TAG_EMPTY: u8 = 0;
+const ORIGIN_TAG_SERVER: u8 = 1;
+const ORIGIN_TAG_CLIENT: u8 = 2;
+
+/// Encodes [CollabOrigin] in compact binary form: a tag byte, followed for clients by zigzag
+/// varint uid and varint length-prefixed device id. Used for the `sender` field of entries
+/// flagged with [UpdateFlags::IS_BINARY_SENDER]. Inverse of [decode_origin].
+pub fn encode_origin(origin: &CollabOrigin) -> Vec<u8> {
+  match origin {
+    CollabOrigin::Empty => vec![ORIGIN_TAG_EMPTY],
+    CollabOrigin::Server => vec![ORIGIN_TAG_SERVER],
+    CollabOrigin::Client(client) => {
+      let mut buf = Vec::with_capacity(1 + 10 + 5 + client.device_id.len());
+      buf.push(ORIGIN_TAG_CLIENT);
+      let uid = ((client.uid << 1) ^ (client.uid >> 63)) as u64;
+      write_varint(&mut buf, uid);
+      write_varint(&mut buf, client.device_id.len() as u64);
+      buf.extend_from_slice(client.device_id.as_bytes());
+      buf
+    },
+  }
+}
+
+/// Decodes [CollabOrigin] encoded with [encode_origin].
+pub fn decode_origin(data: &[u8]) -> RedisResult<CollabOrigin> {
+  let (tag, mut data) = data
+    .split_first()
+    .ok_or_else(|| internal("collab origin is empty"))?;
+  let origin = match *tag {
+    ORIGIN_TAG_EMPTY => CollabOrigin::Empty,
+    ORIGIN_TAG_SERVER => CollabOrigin::Server,
+    ORIGIN_TAG_CLIENT => {
+      let uid = read_varint(&mut data)?;
+      let uid = ((uid >> 1) as i64) ^ -((uid & 1) as i64);
+      let len = read_varint(&mut data)?;
+      if (data.len() as u64) < len {
+        return Err(internal("collab origin device id is truncated"));
+      }
+      let (device_id, rest) = data.split_at(len as usize);
+      let device_id = std::str::from_utf8(device_id)
+        .map_err(|err| internal(format!("collab origin device id is not UTF-8: {}", err)))?;
+      data = rest;
+      CollabOrigin::Client(CollabClient {
+        uid,
+        device_id: device_id.to_string(),
+      })
+    },
+    other => return Err(internal(format!("unknown collab origin tag: {}", other))),
+  };
+  if !data.is_empty() {
+    return Err(internal(format!(
+      "{} trailing bytes after collab origin",
+      data.len()
+    )));
+  }
+  Ok(origin)
+}
+
+/// Parses the `sender` field value, in binary or string form depending on entry `flags`.
+fn parse_sender(value: &[u8], flags: UpdateFlags) -> RedisResult<CollabOrigin> {
+  if flags.has_binary_sender() {
+    decode_origin(value)
+  } else {
+    let value = std::str::from_utf8(value)
+      .map_err(|err| internal(format!("sender is not UTF-8: {}", err)))?;
+    collab_origin_from_str(value)
+  }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buf.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> RedisResult<u64> {
+  let mut value = 0u64;
+  for shift in (0..64).step_by(7) {
+    let (byte, rest) = data
+      .split_first()
+      .ok_or_else(|| internal("collab origin varint is truncated"))?;
+    *data = rest;
+    value |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Ok(value);
+    }
+  }
+  Err(internal("collab origin varint is too long"))
+}
+
+/// Serde adapter for [CollabOrigin], using the same string form as the `sender` field of Redis
+/// stream entries.
+mod collab_origin_serde {
+  use super::{collab_origin_from_str, collab_origin_to_redis_string};
+  use collab::core::origin::CollabOrigin;
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(origin: &CollabOrigin, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&collab_origin_to_redis_string(origin))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<CollabOrigin, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    collab_origin_from_str(&value).map_err(serde::de::Error::custom)
+  }
+}
+
+impl ToRedisArgs for UpdateFlags {
+  #[inline]
+  fn write_redis_args<W>(&self, out: &mut W)
+  where
+    W: ?Sized + RedisWrite,
+  {
+    u8::from(*self).write_redis_args(out)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::error::StreamError;
+  use crate::model::{
+    collab_origin_from_str, collab_origin_to_redis_string, decode_origin, decode_update,
+    encode_origin, is_remote, is_valid_stream_key, sort_by_id, validate_stream_key, AckBatch,
+    AckRef, AppendOptions, AutoClaimReply, AwarenessStreamUpdate, CollabControlEvent,
+    CollabObjectId, CollabStreamUpdate, CollabUpdateEvent, Compression, Compressor, Encoding,
+    EntryField, GroupCreate, GroupInfo, MessageId, MessageIdRange, MultiStreamRead, ParseLimits,
+    PendingEntry, PendingSummary, ReadOutcome, RecentMessages, RedisString, SingleStreamEntry,
+    StreamInfo, StreamKeyed, StreamLen, StreamMessage, StreamMessageByStreamKey, StreamReadId,
+    StreamStats, ToFieldsArray, UpdateFlags, UpdateVariant, ZstdCompressor,
+    CHUNKED_DECOMPRESSION_THRESHOLD, MAX_DECOMPRESSED_SIZE, WIRE_FORMAT_VERSION, ZSTD_MAGIC,
+  };
+  use bytes::Bytes;
+  use collab::core::awareness::AwarenessUpdate;
+  use collab::core::origin::{CollabClient, CollabOrigin};
+  use collab::preclude::updates::decoder::Decode;
+  use collab::preclude::updates::encoder::Encode;
+  use collab::preclude::{Doc, GetString, ReadTxn, StateVector, Text, Transact, Update};
+  use collab_entity::CollabType;
+  use prost::Message;
+  use redis::streams::StreamId;
+  use redis::{FromRedisValue, Value};
+  use std::borrow::Cow;
+  use std::collections::{BTreeMap, HashMap};
+  use std::time::{Duration, UNIX_EPOCH};
+  use tracing_test::traced_test;
+
+  #[test]
+  fn parse_collab_origin_rejects_ambiguous_segments() {
+    assert!(collab_origin_from_str("uid:1|uid:2").is_err());
+    assert!(collab_origin_from_str("device_id:a|device_id:b").is_err());
+    assert!(collab_origin_from_str("foo:1|bar:2").is_err());
+    assert!(collab_origin_from_str("uid:1").is_err());
+  }
+
+  #[test]
+  fn parse_collab_origin_empty() {
+    let expected = CollabOrigin::Empty;
+    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn parse_collab_origin_server() {
+    let expected = CollabOrigin::Server;
+    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn parse_collab_origin_client() {
+    let expected = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn test_collab_update_event_decoding() {
+    let encoded_update = vec![1, 2, 3, 4, 5];
+    let event = super::CollabUpdateEvent::UpdateV1 {
+      encode_update: encoded_update.clone(),
+    };
+    let encoded = event.encode();
+    let decoded = super::CollabUpdateEvent::decode(&encoded).unwrap();
+    assert_eq!(event, decoded);
+  }
+
+  #[test]
+  fn unexpected_value_error_mentions_variant() {
+    let err = StreamMessage::from_redis_value(&Value::Data(vec![0; 12]))
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.contains("expecting Value::Bulk, got Value::Data(12 bytes)"),
+      "{}",
+      err
+    );
+
+    let err = RedisString::from_redis_value(&Value::Data(b"stream\xff\xfekey".to_vec()))
+      .err()
+      .unwrap()
+      .to_string();
+    assert!(
+      err.contains(
+        "invalid UTF-8 string of 11 bytes (valid up to 6): \"stream\u{fffd}\u{fffd}key\""
+      ),
+      "{}",
+      err
+    );
+
+    let err = RedisString::from_redis_value(&Value::Int(5))
+      .err()
+      .unwrap()
+      .to_string();
+    assert!(
+      err.contains("expecting Value::Data, got Value::Int(5)"),
+      "{}",
+      err
+    );
+
+    let err = MessageId::from_redis_value(&Value::Bulk(vec![Value::Nil, Value::Nil]))
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.contains("expecting Value::Data, got Value::Bulk(2 items)"),
+      "{}",
+      err
+    );
+  }
+
+  #[test]
+  fn ack_ref_redis_args_layout() {
+    let ack = AckRef::new("stream", "group", MessageId::new(1631020452097, 3));
+    let args = redis::ToRedisArgs::to_redis_args(&ack);
+    assert_eq!(
+      args,
+      vec![
+        b"stream".to_vec(),
+        b"group".to_vec(),
+        b"1631020452097-3".to_vec()
+      ]
+    );
+  }
+
+  #[test]
+  fn ack_batch_deduplicates_ids() {
+    let mut batch = AckBatch::new();
+    assert!(batch.push(AckRef::new("s1", "g1", MessageId::new(2, 0))));
+    assert!(batch.push(AckRef::new("s1", "g1", MessageId::new(1, 0))));
+    assert!(!batch.push(AckRef::new("s1", "g1", MessageId::new(2, 0))));
+    // same id under a different group is a different acknowledgement
+    assert!(batch.push(AckRef::new("s1", "g2", MessageId::new(2, 0))));
+    assert_eq!(batch.len(), 3);
+
+    let groups: Vec<_> = batch
+      .groups()
+      .map(|group| redis::ToRedisArgs::to_redis_args(&group))
+      .collect();
+    assert_eq!(
+      groups,
+      vec![
+        vec![
+          b"s1".to_vec(),
+          b"g1".to_vec(),
+          b"1-0".to_vec(),
+          b"2-0".to_vec()
+        ],
+        vec![b"s1".to_vec(), b"g2".to_vec(), b"2-0".to_vec()],
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_pending_entries() {
+    let reply = Value::Bulk(vec![
+      Value::Bulk(vec![
+        Value::Data(b"1631020452097-0".to_vec()),
+        Value::Data(b"consumer-1".to_vec()),
+        Value::Int(9104),
+        Value::Int(1),
+      ]),
+      Value::Bulk(vec![
+        Value::Data(b"1631020452098-2".to_vec()),
+        Value::Data(b"consumer-2".to_vec()),
+        Value::Int(120),
+        Value::Int(3),
+      ]),
+    ]);
+    let entries = Vec::<PendingEntry>::from_redis_value(&reply).unwrap();
+    assert_eq!(
+      entries,
+      vec![
+        PendingEntry {
+          id: MessageId::new(1631020452097, 0),
+          consumer: "consumer-1".to_string(),
+          idle_ms: 9104,
+          delivery_count: 1,
+        },
+        PendingEntry {
+          id: MessageId::new(1631020452098, 2),
+          consumer: "consumer-2".to_string(),
+          idle_ms: 120,
+          delivery_count: 3,
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_pending_summary() {
+    let reply = Value::Bulk(vec![
+      Value::Int(3),
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Data(b"1631020452099-1".to_vec()),
+      Value::Bulk(vec![
+        Value::Bulk(vec![
+          Value::Data(b"consumer-1".to_vec()),
+          Value::Data(b"2".to_vec()),
+        ]),
+        Value::Bulk(vec![
+          Value::Data(b"consumer-2".to_vec()),
+          Value::Data(b"1".to_vec()),
+        ]),
+      ]),
+    ]);
+    let summary = PendingSummary::from_redis_value(&reply).unwrap();
+    assert_eq!(summary.count, 3);
+    assert_eq!(summary.min_id, Some(MessageId::new(1631020452097, 0)));
+    assert_eq!(summary.max_id, Some(MessageId::new(1631020452099, 1)));
+    assert_eq!(
+      summary.consumers,
+      vec![("consumer-1".to_string(), 2), ("consumer-2".to_string(), 1)]
+    );
+
+    let empty = Value::Bulk(vec![Value::Int(0), Value::Nil, Value::Nil, Value::Nil]);
+    let summary = PendingSummary::from_redis_value(&empty).unwrap();
+    assert_eq!(summary, PendingSummary::default());
+  }
+
+  #[test]
+  fn parse_auto_claim_reply() {
+    let entry = |id: &str, data: &str| {
+      Value::Bulk(vec![
+        Value::Data(id.as_bytes().to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"data".to_vec()),
+          Value::Data(data.as_bytes().to_vec()),
+        ]),
+      ])
+    };
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452099-0".to_vec()),
+      Value::Bulk(vec![
+        entry("1631020452097-0", "a"),
+        entry("1631020452098-1", "b"),
+      ]),
+      Value::Bulk(vec![Value::Data(b"1631020452096-0".to_vec())]),
+    ]);
+    let reply = AutoClaimReply::from_redis_value(&reply).unwrap();
+    assert_eq!(reply.next_cursor, MessageId::new(1631020452099, 0));
+    assert!(!reply.is_complete());
+    assert_eq!(reply.messages.len(), 2);
+    assert_eq!(reply.messages[0].id, MessageId::new(1631020452097, 0));
+    assert_eq!(reply.messages[0].data.as_ref(), b"a");
+    assert_eq!(reply.messages[1].id, MessageId::new(1631020452098, 1));
+    assert_eq!(reply.messages[1].data.as_ref(), b"b");
+    assert_eq!(reply.deleted, vec![MessageId::new(1631020452096, 0)]);
+
+    let done = Value::Bulk(vec![
+      Value::Data(b"0-0".to_vec()),
+      Value::Bulk(vec![]),
+      Value::Bulk(vec![]),
+    ]);
+    let done = AutoClaimReply::from_redis_value(&done).unwrap();
+    assert!(done.is_complete());
+    assert!(done.messages.is_empty());
+    assert!(done.deleted.is_empty());
+  }
+
+  fn xinfo_stream_reply(first_entry: Value, last_entry: Value, length: i64) -> Value {
+    let field = |name: &str| Value::Data(name.as_bytes().to_vec());
+    Value::Bulk(vec![
+      field("length"),
+      Value::Int(length),
+      field("radix-tree-keys"),
+      Value::Int(1),
+      field("radix-tree-nodes"),
+      Value::Int(2),
+      field("last-generated-id"),
+      field("1631020452099-1"),
+      field("max-deleted-entry-id"),
+      field("0-0"),
+      field("entries-added"),
+      Value::Int(length),
+      field("recorded-first-entry-id"),
+      field("1631020452097-0"),
+      field("groups"),
+      Value::Int(2),
+      field("first-entry"),
+      first_entry,
+      field("last-entry"),
+      last_entry,
+    ])
+  }
+
+  #[test]
+  fn parse_group_info() {
+    let field = |name: &str| Value::Data(name.as_bytes().to_vec());
+    let group = |name: &str, lag: Option<Value>| {
+      let mut fields = vec![
+        field("name"),
+        field(name),
+        field("consumers"),
+        Value::Int(2),
+        field("pending"),
+        Value::Int(5),
+        field("last-delivered-id"),
+        field("1631020452097-3"),
+      ];
+      if let Some(lag) = lag {
+        fields.extend([field("entries-read"), Value::Int(10), field("lag"), lag]);
+      }
+      Value::Bulk(fields)
+    };
+    let reply = Value::Bulk(vec![
+      group("g1", Some(Value::Int(7))),
+      group("g2", Some(Value::Nil)),
+      group("g3", None),
+    ]);
+    let groups = Vec::<GroupInfo>::from_redis_value(&reply).unwrap();
+    assert_eq!(
+      groups[0],
+      GroupInfo {
+        name: "g1".to_string(),
+        consumers: 2,
+        pending: 5,
+        last_delivered_id: MessageId::new(1631020452097, 3),
+        lag: Some(7),
+      }
+    );
+    assert_eq!(groups[1].lag, None);
+    // older Redis versions don't report lag at all
+    assert_eq!(groups[2].name, "g3");
+    assert_eq!(groups[2].lag, None);
+
+    let missing = Value::Bulk(vec![field("name"), field("g1")]);
+    assert!(GroupInfo::from_redis_value(&missing).is_err());
+  }
+
+  #[test]
+  fn parse_stream_len() {
+    assert_eq!(
+      StreamLen::from_redis_value(&Value::Int(42)).unwrap(),
+      StreamLen(42)
+    );
+    assert_eq!(
+      StreamLen::from_redis_value(&Value::Int(0)).unwrap(),
+      StreamLen(0)
+    );
+    assert!(StreamLen::from_redis_value(&Value::Int(-1)).is_err());
+    assert!(StreamLen::from_redis_value(&Value::Data(b"42".to_vec())).is_err());
+  }
+
+  #[test]
+  fn parse_stream_stats() {
+    let reply = xinfo_stream_reply(Value::Nil, Value::Nil, 3);
+    let stats = StreamStats::from_redis_value(&reply).unwrap();
+    // `max-deleted-entry-id` of `0-0` means nothing was deleted yet
+    assert_eq!(
+      stats,
+      StreamStats {
+        length: 3,
+        max_deleted_id: None,
+      }
+    );
+
+    let field = |name: &str| Value::Data(name.as_bytes().to_vec());
+    let reply = Value::Bulk(vec![
+      field("length"),
+      Value::Int(10),
+      field("max-deleted-entry-id"),
+      field("1631020452097-4"),
+    ]);
+    let stats = StreamStats::from_redis_value(&reply).unwrap();
+    assert_eq!(stats.length, 10);
+    assert_eq!(stats.max_deleted_id, Some(MessageId::new(1631020452097, 4)));
+
+    // Redis < 7.0
+    let reply = Value::Bulk(vec![field("length"), Value::Int(10)]);
+    let stats = StreamStats::from_redis_value(&reply).unwrap();
+    assert_eq!(stats.max_deleted_id, None);
+  }
+
+  #[test]
+  fn parse_stream_info() {
+    let entry = |id: &str| {
+      Value::Bulk(vec![
+        Value::Data(id.as_bytes().to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"data".to_vec()),
+          Value::Data(b"payload".to_vec()),
+        ]),
+      ])
+    };
+    let reply = xinfo_stream_reply(entry("1631020452097-0"), entry("1631020452099-1"), 3);
+    let info = StreamInfo::from_redis_value(&reply).unwrap();
+    assert_eq!(
+      info,
+      StreamInfo {
+        length: 3,
+        first_entry: Some(MessageId::new(1631020452097, 0)),
+        last_entry: Some(MessageId::new(1631020452099, 1)),
+        last_generated_id: MessageId::new(1631020452099, 1),
+        groups: 2,
+      }
+    );
+  }
+
+  #[test]
+  fn parse_empty_stream_info() {
+    let reply = xinfo_stream_reply(Value::Nil, Value::Nil, 0);
+    let info = StreamInfo::from_redis_value(&reply).unwrap();
+    assert_eq!(info.length, 0);
+    assert_eq!(info.first_entry, None);
+    assert_eq!(info.last_entry, None);
+    assert_eq!(info.last_generated_id, MessageId::new(1631020452099, 1));
+  }
+
+  #[test]
+  fn minid_before_retention_window() {
+    let now = UNIX_EPOCH + Duration::from_millis(1631020452097);
+    let cutoff = MessageId::minid_before(now, Duration::from_secs(24 * 60 * 60));
+    assert_eq!(cutoff, MessageId::new(1631020452097 - 86_400_000, 0));
+  }
+
+  #[test]
+  fn minid_before_clamps_on_underflow() {
+    let now = UNIX_EPOCH + Duration::from_secs(100);
+    let cutoff = MessageId::minid_before(now, Duration::from_secs(24 * 60 * 60));
+    assert_eq!(cutoff, MessageId::MIN);
+  }
+
+  #[test]
+  fn collab_update_event_compression_round_trip() {
+    let encode_update: Vec<u8> = (0..64 * 1024).map(|i| (i % 7) as u8).collect();
+    let event = CollabUpdateEvent::UpdateV1 { encode_update };
+
+    let uncompressed = event.encode();
+    let compressed = event.encode_compressed(3).unwrap();
+    assert!(compressed.len() < uncompressed.len() / 10);
+
+    assert_eq!(CollabUpdateEvent::decode(&uncompressed).unwrap(), event);
+    assert_eq!(CollabUpdateEvent::decode(&compressed).unwrap(), event);
+
+    // compressed events are always prefixed with a format byte
+    let unprefixed = zstd::encode_all(event.to_proto().encode_to_vec().as_slice(), 3).unwrap();
+    assert!(CollabUpdateEvent::decode(&unprefixed).is_err());
+  }
+
+  #[test]
+  fn collab_update_event_legacy_bincode_decoding() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    let legacy = bincode::serialize(&event).unwrap();
+    assert_eq!(CollabUpdateEvent::decode(&legacy).unwrap(), event);
+  }
+
+  #[test]
+  fn collab_update_event_strict_decoding() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    assert_eq!(
+      CollabUpdateEvent::decode_strict(&event.encode()).unwrap(),
+      event
+    );
+    let compressed = event.encode_compressed(3).unwrap();
+    assert_eq!(
+      CollabUpdateEvent::decode_strict(&compressed).unwrap(),
+      event
+    );
+    let legacy_proto = event.to_proto().encode_to_vec();
+    assert_eq!(
+      CollabUpdateEvent::decode_strict(&legacy_proto).unwrap(),
+      event
+    );
+
+    let legacy_bincode = bincode::serialize(&event).unwrap();
+    assert!(CollabUpdateEvent::decode_strict(&legacy_bincode).is_err());
+    assert_eq!(CollabUpdateEvent::decode(&legacy_bincode).unwrap(), event);
+  }
+
+  #[test]
+  fn collab_update_event_legacy_proto_decoding() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    let legacy = event.to_proto().encode_to_vec();
+    assert_eq!(CollabUpdateEvent::decode(&legacy).unwrap(), event);
+  }
+
+  #[test]
+  fn collab_update_event_dispatches_on_format_byte() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    let encoded = event.encode();
+    assert_eq!(encoded[0], super::EVENT_FORMAT_PROTO);
+    assert_eq!(&encoded[1..3], WIRE_FORMAT_VERSION.to_le_bytes().as_slice());
+    assert_eq!(&encoded[3..], event.to_proto().encode_to_vec().as_slice());
+    assert_eq!(CollabUpdateEvent::decode(&encoded).unwrap(), event);
+
+    let compressed = event.encode_compressed(3).unwrap();
+    assert_eq!(compressed[0], super::EVENT_FORMAT_PROTO_ZSTD);
+    assert_eq!(CollabUpdateEvent::decode(&compressed).unwrap(), event);
+
+    // once prefixed with a format byte, payload is never retried as bincode
+    let mut prefixed_bincode = vec![super::EVENT_FORMAT_PROTO];
+    prefixed_bincode.extend(WIRE_FORMAT_VERSION.to_le_bytes());
+    prefixed_bincode.extend(bincode::serialize(&event).unwrap());
+    assert!(CollabUpdateEvent::decode(&prefixed_bincode).is_err());
+  }
+
+  #[test]
+  fn collab_update_event_decompression_is_bounded() {
+    use std::io::Write;
+    // zstd bomb: few KiB inflating to just over the limit
+    let mut bomb = vec![super::EVENT_FORMAT_PROTO_ZSTD];
+    bomb.extend(WIRE_FORMAT_VERSION.to_le_bytes());
+    let mut encoder = zstd::stream::Encoder::new(bomb, 3).unwrap();
+    let block = vec![0u8; 1024 * 1024];
+    for _ in 0..=MAX_DECOMPRESSED_SIZE / block.len() {
+      encoder.write_all(&block).unwrap();
+    }
+    let bomb = encoder.finish().unwrap();
+    assert!(bomb.len() < 64 * 1024);
+    assert!(matches!(
+      CollabUpdateEvent::decode(&bomb),
+      Err(StreamError::TooLarge(MAX_DECOMPRESSED_SIZE))
+    ));
+  }
+
+  #[test]
+  fn collab_update_event_newer_wire_version_rejected() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    let proto = event.to_proto().encode_to_vec();
+    let mut newer = vec![super::EVENT_FORMAT_PROTO];
+    newer.extend((WIRE_FORMAT_VERSION + 1).to_le_bytes());
+    newer.extend(&proto);
+    assert!(matches!(
+      CollabUpdateEvent::decode(&newer),
+      Err(StreamError::Unsupported(_))
+    ));
+
+    let mut current = vec![super::EVENT_FORMAT_PROTO];
+    current.extend(WIRE_FORMAT_VERSION.to_le_bytes());
+    current.extend(&proto);
+    assert_eq!(CollabUpdateEvent::decode(&current).unwrap(), event);
+  }
+
+  #[test]
+  fn collab_update_event_peek_variant() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: vec![1, 2, 3, 4, 5],
+    };
+    let encoded = event.encode();
+    assert_eq!(
+      CollabUpdateEvent::peek_variant(&encoded).unwrap(),
+      UpdateVariant::V1
+    );
+    let compressed = event.encode_compressed(3).unwrap();
+    assert_eq!(
+      CollabUpdateEvent::peek_variant(&compressed).unwrap(),
+      UpdateVariant::V1
+    );
+    let legacy_proto = event.to_proto().encode_to_vec();
+    assert_eq!(
+      CollabUpdateEvent::peek_variant(&legacy_proto).unwrap(),
+      UpdateVariant::V1
+    );
+    // only the leading field key of a compressed event is decompressed, the rest of the payload
+    // is never looked at (here it's shorter than declared, so full decoding fails)
+    let mut partial = vec![super::EVENT_FORMAT_PROTO_ZSTD];
+    partial.extend(WIRE_FORMAT_VERSION.to_le_bytes());
+    let mut proto = Vec::new();
+    prost::encoding::encode_key(1, prost::encoding::WireType::LengthDelimited, &mut proto);
+    prost::encoding::encode_varint(1024 * 1024, &mut proto);
+    proto.resize(proto.len() + 1024, 0);
+    partial.extend(zstd::encode_all(proto.as_slice(), 3).unwrap());
+    assert!(CollabUpdateEvent::decode(&partial).is_err());
+    assert_eq!(
+      CollabUpdateEvent::peek_variant(&partial).unwrap(),
+      UpdateVariant::V1
+    );
+    let legacy_bincode = bincode::serialize(&event).unwrap();
+    assert_eq!(
+      CollabUpdateEvent::peek_variant(&legacy_bincode).unwrap(),
+      UpdateVariant::V1
+    );
+  }
+
+  #[test]
+  fn collab_update_event_peek_variant_malformed() {
+    assert!(CollabUpdateEvent::peek_variant(&[]).is_err());
+    assert!(CollabUpdateEvent::peek_variant(&[super::EVENT_FORMAT_PROTO]).is_err());
+    // length-delimited field claiming more bytes than available
+    let mut truncated = vec![super::EVENT_FORMAT_PROTO];
+    truncated.extend(WIRE_FORMAT_VERSION.to_le_bytes());
+    truncated.extend([0x0A, 0x10, 1]);
+    assert!(CollabUpdateEvent::peek_variant(&truncated).is_err());
+    assert!(CollabUpdateEvent::peek_variant(&[0xFF, 0xFF, 0xFF]).is_err());
+  }
+
+  #[test]
+  fn collab_update_event_batch_round_trip() {
+    let events: Vec<_> = (0..3u8)
+      .map(|i| CollabUpdateEvent::UpdateV1 {
+        encode_update: vec![i; i as usize + 1],
+      })
+      .collect();
+
+    let empty = CollabUpdateEvent::encode_batch(&[]);
+    assert!(CollabUpdateEvent::decode_batch(&empty).unwrap().is_empty());
+
+    let single = CollabUpdateEvent::encode_batch(&events[..1]);
+    assert_eq!(
+      CollabUpdateEvent::decode_batch(&single).unwrap(),
+      events[..1]
+    );
+
+    let multi = CollabUpdateEvent::encode_batch(&events);
+    assert_eq!(CollabUpdateEvent::decode_batch(&multi).unwrap(), events);
+  }
+
+  #[test]
+  fn collab_update_event_truncated_batch() {
+    let events = vec![
+      CollabUpdateEvent::UpdateV1 {
+        encode_update: vec![1, 2, 3],
+      },
+      CollabUpdateEvent::UpdateV1 {
+        encode_update: vec![4, 5, 6],
+      },
+    ];
+    let encoded = CollabUpdateEvent::encode_batch(&events);
+    // cut in the middle of the last event payload
+    let result = CollabUpdateEvent::decode_batch(&encoded[..encoded.len() - 1]);
+    assert!(matches!(result, Err(StreamError::InvalidFormat)));
+    // cut in the middle of the length prefix
+    let first_len = encoded.len() / 2;
+    let result = CollabUpdateEvent::decode_batch(&encoded[..first_len + 2]);
+    assert!(matches!(result, Err(StreamError::InvalidFormat)));
+  }
+
+  #[test]
+  fn collab_stream_update_clone_eq() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], sender, UpdateFlags::IS_COMPRESSED);
+    let cloned = update.clone();
+    assert!(update == cloned);
+  }
+
+  #[test]
+  fn collab_stream_update_flags_inequality() {
+    let v1 = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, 0);
+    let v2 = CollabStreamUpdate::new(
+      vec![1, 2, 3],
+      CollabOrigin::Server,
+      UpdateFlags::IS_V2_ENCODED,
+    );
+    assert!(v1 != v2);
+  }
+
+  #[cfg(not(feature = "redact"))]
+  #[test]
+  fn collab_stream_update_debug_omits_payload() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let update = CollabStreamUpdate::new(vec![7; 1024], sender.clone(), 0);
+    let debug = format!("{:?}", update);
+    assert!(debug.contains("test-device"), "{}", debug);
+    assert!(debug.contains("flags: .v1"), "{}", debug);
+    assert!(debug.contains("data_len: 1024"), "{}", debug);
+    assert!(!debug.contains("7, 7"), "{}", debug);
+
+    let update = AwarenessStreamUpdate {
+      data: vec![7; 1024],
+      sender,
+      expires_at: None,
+    };
+    let debug = format!("{:?}", update);
+    assert!(debug.contains("test-device"), "{}", debug);
+    assert!(debug.contains("data_len: 1024"), "{}", debug);
+    assert!(!debug.contains("7, 7"), "{}", debug);
+  }
+
+  #[test]
+  fn collab_stream_update_builder_defaults() {
+    let update = CollabStreamUpdate::builder(vec![1, 2, 3]).build().unwrap();
+    assert_eq!(
+      update,
+      CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Empty, UpdateFlags::default())
+    );
+  }
+
+  #[test]
+  fn collab_stream_update_builder_custom() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let data = vec![1; 1024];
+    let update = CollabStreamUpdate::builder(data.clone())
+      .from_server()
+      .sender(sender.clone())
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert_eq!(update.sender, sender);
+    assert!(update.flags.is_v2_encoded());
+    assert!(update.flags.is_compressed());
+    assert_eq!(zstd::decode_all(update.data.as_slice()).unwrap(), data);
+
+    let update = CollabStreamUpdate::builder(data)
+      .from_server()
+      .build()
+      .unwrap();
+    assert_eq!(update.sender, CollabOrigin::Server);
+  }
+
+  fn sample_update(content: &str) -> Update {
+    let doc = Doc::with_client_id(1);
+    let text = doc.get_or_insert_text("text");
+    let mut txn = doc.transact_mut();
+    text.insert(&mut txn, 0, content);
+    Update::decode_v1(&txn.encode_update_v1()).unwrap()
+  }
+
+  #[test]
+  fn new_validated_accepts_v1_update() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::new_validated(data.clone(), CollabOrigin::Server, 0).unwrap();
+    assert_eq!(update.data, data);
+  }
+
+  #[test]
+  fn collab_stream_update_from_update() {
+    let update = sample_update("hello");
+    for use_v2 in [false, true] {
+      let stream_update = CollabStreamUpdate::from_update(&update, CollabOrigin::Server, use_v2);
+      assert_eq!(stream_update.flags.is_v2_encoded(), use_v2);
+      assert_eq!(
+        stream_update.into_update().unwrap().encode_v1(),
+        update.encode_v1()
+      );
+    }
+  }
+
+  #[test]
+  fn mixed_v1_and_v2_updates_decode() {
+    let update = sample_update("hello");
+
+    let v2 = CollabStreamUpdate::new_v2(update.encode_v2(), CollabOrigin::Server);
+    assert!(v2.flags.is_v2_encoded());
+    assert_eq!(v2.into_update().unwrap().encode_v1(), update.encode_v1());
+
+    let v1 = CollabStreamUpdate::new(update.encode_v1(), CollabOrigin::Server, 0);
+    assert!(v1.flags.is_v1_encoded());
+    assert_eq!(v1.into_update().unwrap().encode_v1(), update.encode_v1());
+  }
+
+  #[test]
+  fn encoding_from_flags_decodes_update() {
+    let update = sample_update("hello");
+    let v1 = UpdateFlags::default().encoding();
+    let v2 = UpdateFlags::from(UpdateFlags::IS_V2_ENCODED).encoding();
+    assert_eq!(v1, Encoding::V1);
+    assert_eq!(v2, Encoding::V2);
+    assert_eq!(
+      v1.decode(&update.encode_v1()).unwrap().encode_v1(),
+      update.encode_v1()
+    );
+    assert_eq!(
+      v2.decode(&update.encode_v2()).unwrap().encode_v1(),
+      update.encode_v1()
+    );
+  }
+
+  #[test]
+  fn collab_stream_update_serde_round_trip() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 42,
+      device_id: "device|1".to_string(),
+    });
+    let update = CollabStreamUpdate::builder(sample_update("hello").encode_v2())
+      .sender(sender)
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+
+    let bytes = bincode::serialize(&update).unwrap();
+    let decoded: CollabStreamUpdate = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(decoded, update);
+
+    let json = serde_json::to_string(&update).unwrap();
+    let decoded: CollabStreamUpdate = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, update);
+    assert_eq!(decoded.flags.to_string(), ".v2.zstd");
+    assert_eq!(decoded.sender_uid(), Some(42));
+  }
+
+  #[test]
+  fn collab_update_event_into_stream_update_round_trip() {
+    let data = sample_update("hello").encode_v1();
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: data.clone(),
+    };
+    let update = CollabStreamUpdate::from_event(event.clone(), CollabOrigin::Server);
+    assert_eq!(update.data, data);
+    assert_eq!(update.flags, UpdateFlags::default());
+    assert_eq!(update.sender, CollabOrigin::Server);
+    assert_eq!(update.to_event().unwrap(), event);
+  }
+
+  #[test]
+  fn collab_stream_update_into_event_round_trip() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::new(data.clone(), CollabOrigin::Server, 0);
+    let event = update.to_event().unwrap();
+    assert_eq!(
+      CollabStreamUpdate::from_event(event, CollabOrigin::Server),
+      update
+    );
+
+    let compressed = CollabStreamUpdate::builder(data.clone())
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert_eq!(
+      compressed.to_event().unwrap(),
+      CollabUpdateEvent::UpdateV1 {
+        encode_update: data
+      }
+    );
+
+    let v2 = CollabStreamUpdate::new_v2(sample_update("hello").encode_v2(), CollabOrigin::Server);
+    assert!(matches!(v2.to_event(), Err(StreamError::Unsupported(_))));
+  }
+
+  #[test]
+  #[traced_test]
+  fn decode_failure_is_logged() {
+    let update = CollabStreamUpdate::new(vec![255, 255, 255], CollabOrigin::Server, 0);
+    assert!(update.into_update().is_err());
+    assert!(logs_contain("failed to decode collab stream update"));
+    assert!(logs_contain("flags=.v1"));
+    assert!(logs_contain("len=3"));
+  }
+
+  #[test]
+  fn collab_stream_update_from_headers() {
+    let update = CollabStreamUpdate::new(
+      sample_update("hello").encode_v1(),
+      CollabOrigin::Client(CollabClient {
+        uid: 7,
+        device_id: "device".to_string(),
+      }),
+      UpdateFlags::IS_V2_ENCODED,
+    );
+    let headers: HashMap<String, Vec<u8>> = update
+      .to_fields_with_checksum()
+      .into_iter()
+      .map(|(key, value)| (key.to_string(), value))
+      .collect();
+    assert_eq!(CollabStreamUpdate::from_headers(&headers).unwrap(), update);
+
+    let mut corrupted = headers.clone();
+    corrupted.insert("data".to_string(), vec![1, 2, 3]);
+    assert!(matches!(
+      CollabStreamUpdate::from_headers(&corrupted),
+      Err(StreamError::ChecksumMismatch { .. })
+    ));
+
+    let data_only = HashMap::from([("data".to_string(), vec![1, 2, 3])]);
+    let update = CollabStreamUpdate::from_headers(&data_only).unwrap();
+    assert_eq!(update.sender, CollabOrigin::Empty);
+    assert_eq!(update.flags, UpdateFlags::default());
+    assert!(CollabStreamUpdate::from_headers(&HashMap::new()).is_err());
+  }
+
+  #[test]
+  fn redis_string_utf8() {
+    let valid = RedisString::from_redis_value(&Value::Data("stream-ключ".as_bytes().to_vec()));
+    assert_eq!(valid.unwrap().to_string(), "stream-ключ");
+
+    let invalid = Value::Data(vec![b'a', 0xff, b'b']);
+    assert!(RedisString::from_redis_value(&invalid).is_err());
+    let lossy = RedisString::from_redis_value_lossy(&invalid).unwrap();
+    assert_eq!(lossy.to_string(), "a\u{fffd}b");
+    assert!(RedisString::from_redis_value_lossy(&Value::Nil).is_err());
+  }
+
+  #[test]
+  fn awareness_update_round_trip() {
+    // single client (id: 1) with clock 3 and JSON state `{"user":"a"}`
+    let json = br#"{"user":"a"}"#;
+    let mut encoded = vec![1, 1, 3, json.len() as u8];
+    encoded.extend_from_slice(json);
+    let update = AwarenessUpdate::decode_v1(&encoded).unwrap();
+
+    let stream_update = AwarenessStreamUpdate::from_awareness(&update, CollabOrigin::Server);
+    assert_eq!(stream_update.data, encoded);
+    assert_eq!(stream_update.sender, CollabOrigin::Server);
+    assert_eq!(stream_update.expires_at, None);
+
+    let decoded = stream_update.into_awareness_update().unwrap();
+    assert_eq!(decoded.encode_v1(), update.encode_v1());
+
+    let garbage = AwarenessStreamUpdate {
+      data: vec![0xff; 4],
+      sender: CollabOrigin::Server,
+      expires_at: None,
+    };
+    assert!(garbage.into_awareness_update().is_err());
+  }
+
+  #[test]
+  fn coalesce_awareness_updates_by_client() {
+    let client = |uid: i64| {
+      CollabOrigin::Client(CollabClient {
+        uid,
+        device_id: "device".to_string(),
+      })
+    };
+    let update = |sender: CollabOrigin, data: u8| AwarenessStreamUpdate {
+      data: vec![data],
+      sender,
+      expires_at: None,
+    };
+    let updates = vec![
+      update(client(1), 1),
+      update(client(2), 2),
+      update(CollabOrigin::Server, 3),
+      update(client(1), 4),
+      update(client(2), 5),
+      update(CollabOrigin::Server, 6),
+      update(client(1), 7),
+    ];
+    let coalesced: Vec<_> = AwarenessStreamUpdate::coalesce(updates)
+      .into_iter()
+      .map(|update| (update.sender, update.data[0]))
+      .collect();
+    assert_eq!(
+      coalesced,
+      vec![
+        (client(1), 7),
+        (client(2), 5),
+        (CollabOrigin::Server, 3),
+        (CollabOrigin::Server, 6),
+      ]
+    );
+  }
+
+  #[test]
+  fn awareness_update_expiry() {
+    let now = UNIX_EPOCH + Duration::from_millis(1631020452097);
+    let parse = |fields: Vec<(&'static str, Vec<u8>)>| {
+      AwarenessStreamUpdate::try_from(redis_fields(fields)).unwrap()
+    };
+
+    let expired = parse(vec![
+      ("data", vec![1, 2, 3]),
+      ("sender", b"server".to_vec()),
+      ("expires_at", b"1631020452000".to_vec()),
+    ]);
+    assert_eq!(expired.expires_at, Some(1631020452000));
+    assert!(expired.is_expired(now));
+
+    let live = parse(vec![
+      ("data", vec![1, 2, 3]),
+      ("expires_at", b"1631020453000".to_vec()),
+    ]);
+    assert!(!live.is_expired(now));
+
+    let legacy = parse(vec![
+      ("data", vec![1, 2, 3]),
+      ("sender", b"server".to_vec()),
+    ]);
+    assert_eq!(legacy.expires_at, None);
+    assert!(!legacy.is_expired(now + Duration::from_secs(365 * 24 * 3600)));
+
+    let result = AwarenessStreamUpdate::try_from(redis_fields(vec![
+      ("data", vec![1, 2, 3]),
+      ("expires_at", b"tomorrow".to_vec()),
+    ]));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn stream_keyed_matches_stream_key_format() {
+    struct Object {
+      workspace_id: String,
+      object_id: String,
+    }
+    impl CollabObjectId for Object {
+      fn workspace_id(&self) -> &str {
+        &self.workspace_id
+      }
+      fn object_id(&self) -> &str {
+        &self.object_id
+      }
+    }
+
+    let obj = Object {
+      workspace_id: "w1".to_string(),
+      object_id: "o1".to_string(),
+    };
+    assert_eq!(obj.updates_key(), "af:w1:o1:updates");
+    assert_eq!(
+      obj.updates_key(),
+      CollabStreamUpdate::stream_key("w1", "o1")
+    );
+    assert_eq!(obj.awareness_key(), "af:w1:o1:awareness");
+    assert_eq!(
+      obj.awareness_key(),
+      AwarenessStreamUpdate::stream_key("w1", "o1")
+    );
+  }
+
+  #[test]
+  fn validate_collab_stream_keys() {
+    for key in [
+      "af:w1:o1:updates",
+      "af:w1:o1:awareness",
+      "af:w1:o1:control",
+      CollabStreamUpdate::stream_key("w1", "o1").as_str(),
+      AwarenessStreamUpdate::stream_key("w1", "o1").as_str(),
+      CollabStreamUpdate::typed_stream_key("w1", "o1", CollabType::Document).as_str(),
+    ] {
+      assert!(is_valid_stream_key(key), "{}", key);
+      validate_stream_key(key).unwrap();
+    }
+
+    for key in [
+      "",
+      "af:",
+      "w1:o1:updates",
+      "xx:w1:o1:updates",
+      "af:w1:updates",
+      "af::o1:updates",
+      "af:w1::updates",
+      "af:w1:o1:snapshot_lease",
+      "af:w1:o1:updates:extra",
+      "af:w1:o1:Updates",
+    ] {
+      assert!(!is_valid_stream_key(key), "{}", key);
+    }
+    let err = validate_stream_key("af:w1:o1:snapshot_lease").unwrap_err();
+    assert!(err.to_string().contains("unknown stream kind"), "{}", err);
+  }
+
+  #[test]
+  fn typed_stream_key_round_trip() {
+    for collab_type in [CollabType::Document, CollabType::Database] {
+      let key = CollabStreamUpdate::typed_stream_key("w1", "o1", collab_type.clone());
+      assert_ne!(key, CollabStreamUpdate::stream_key("w1", "o1"));
+      let (workspace_id, object_id, parsed) =
+        CollabStreamUpdate::parse_typed_stream_key(&key).unwrap();
+      assert_eq!(workspace_id, "w1");
+      assert_eq!(object_id, "o1");
+      assert_eq!(parsed, collab_type);
+    }
+    let document = CollabStreamUpdate::typed_stream_key("w1", "o1", CollabType::Document);
+    let database = CollabStreamUpdate::typed_stream_key("w1", "o1", CollabType::Database);
+    assert_ne!(document, database);
+
+    let untyped = CollabStreamUpdate::stream_key("w1", "o1");
+    assert!(CollabStreamUpdate::parse_typed_stream_key(&untyped).is_none());
+  }
+
+  #[cfg(feature = "metrics")]
+  #[test]
+  fn decode_outcomes_are_counted() {
+    use crate::metrics::{decode_counter, DecodeLabel};
+    let label = |outcome| DecodeLabel {
+      encoding: "v2",
+      compression: "zstd",
+      outcome,
+    };
+    let ok_before = decode_counter().get_or_create(&label("ok")).get();
+    let error_before = decode_counter().get_or_create(&label("error")).get();
+
+    let update = CollabStreamUpdate::builder(sample_update("hello").encode_v2())
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    update.into_update().unwrap();
+    let corrupted = CollabStreamUpdate::new(
+      vec![1, 2, 3],
+      CollabOrigin::Empty,
+      UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED,
+    );
+    assert!(corrupted.into_update().is_err());
+
+    assert!(decode_counter().get_or_create(&label("ok")).get() > ok_before);
+    assert!(decode_counter().get_or_create(&label("error")).get() > error_before);
+  }
+
+  #[test]
+  fn reencode_between_v1_and_v2() {
+    let expected = sample_update("hello");
+    let sender = CollabOrigin::Server;
+
+    let v2 = CollabStreamUpdate::new_v2(expected.encode_v2(), sender.clone());
+    let v1 = v2.reencode(Encoding::V1).unwrap();
+    assert_eq!(v1.flags.encoding(), Encoding::V1);
+    assert_eq!(v1.data, expected.encode_v1());
+    assert_eq!(v1.sender, sender);
+    assert_eq!(v1.clone().into_update().unwrap(), expected);
+
+    let v2 = v1.reencode(Encoding::V2).unwrap();
+    assert_eq!(v2.flags, UpdateFlags::from(UpdateFlags::IS_V2_ENCODED));
+    assert_eq!(v2.into_update().unwrap(), expected);
+
+    let compressed = CollabStreamUpdate::builder(expected.encode_v2())
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    let v1 = compressed.reencode(Encoding::V1).unwrap();
+    assert_eq!(v1.flags.to_string(), ".v1.zstd");
+    assert_eq!(v1.into_update().unwrap(), expected);
+
+    let garbage = CollabStreamUpdate::new_v2(vec![1, 2, 3], sender);
+    assert!(garbage.reencode(Encoding::V1).is_err());
+  }
+
+  #[test]
+  fn collab_origin_binary_round_trip() {
+    let origins = [
+      CollabOrigin::Empty,
+      CollabOrigin::Server,
+      CollabOrigin::Client(CollabClient {
+        uid: 296870234256904192,
+        device_id: "0fd3b0ec-8ba2-4e32-9a4f-1a2b3c4d5e6f".to_string(),
+      }),
+      CollabOrigin::Client(CollabClient {
+        uid: -1,
+        device_id: "a|b".to_string(),
+      }),
+    ];
+    for origin in origins {
+      let binary = encode_origin(&origin);
+      assert_eq!(decode_origin(&binary).unwrap(), origin);
+      let string = collab_origin_to_redis_string(&origin);
+      assert_eq!(collab_origin_from_str(&string).unwrap(), origin);
+      if let CollabOrigin::Client(_) = origin {
+        assert!(
+          binary.len() < string.len(),
+          "{} vs {}",
+          binary.len(),
+          string.len()
+        );
+      }
+    }
+
+    assert!(decode_origin(&[]).is_err());
+    assert!(decode_origin(&[9]).is_err());
+    assert!(decode_origin(&[2, 2, 10, b'a']).is_err());
+    assert!(decode_origin(&[1, 0]).is_err());
+  }
+
+  #[test]
+  fn collab_stream_update_binary_sender() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 7,
+      device_id: "device".to_string(),
+    });
+    let update = CollabStreamUpdate::new_v2(sample_update("hello").encode_v2(), sender.clone())
+      .with_binary_sender();
+    assert!(update.flags.has_binary_sender());
+    let fields = update.to_fields();
+    assert_eq!(fields[1], ("sender", encode_origin(&sender)));
+
+    let headers: HashMap<String, Vec<u8>> = fields
+      .into_iter()
+      .map(|(key, value)| (key.to_string(), value))
+      .collect();
+    assert_eq!(CollabStreamUpdate::from_headers(&headers).unwrap(), update);
+
+    let values: HashMap<String, Value> = headers
+      .iter()
+      .map(|(key, value)| (key.clone(), Value::Data(value.clone())))
+      .collect();
+    let parsed = CollabStreamUpdate::try_from(values).unwrap();
+    assert_eq!(parsed.sender, sender);
+    assert_eq!(
+      parsed.into_update().unwrap().encode_v1(),
+      sample_update("hello").encode_v1()
+    );
+
+    // string form is still used when flag is not set
+    let update = CollabStreamUpdate::new_v2(vec![1], sender.clone());
+    assert_eq!(
+      update.to_fields()[1],
+      ("sender", b"uid:7|device_id:device".to_vec())
+    );
+  }
+
+  #[test]
+  fn collab_stream_update_fields_array() {
+    let update = CollabStreamUpdate::new(
+      vec![1, 2, 3],
+      CollabOrigin::Client(CollabClient {
+        uid: 7,
+        device_id: "device".to_string(),
+      }),
+      UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED,
+    );
+    let fields = update.to_fields_array();
+    assert_eq!(
+      fields,
+      [
+        EntryField::borrowed("data", &[1, 2, 3]),
+        EntryField::owned("sender", b"uid:7|device_id:device".to_vec()),
+        EntryField::owned("flags", b"3".to_vec()),
+      ]
+    );
+    // payload is borrowed, not copied
+    match &fields[0].value {
+      Some(Cow::Borrowed(data)) => assert_eq!(data.as_ptr(), update.data.as_ptr()),
+      other => panic!("expected borrowed payload, got {:?}", other),
+    }
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&fields.as_slice()),
+      redis::ToRedisArgs::to_redis_args(&update.to_fields())
+    );
+
+    let mut awareness = AwarenessStreamUpdate {
+      data: vec![4, 5],
+      sender: CollabOrigin::Server,
+      expires_at: Some(1631020452097),
+    };
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&awareness.to_fields_array().as_slice()),
+      vec![
+        b"data".to_vec(),
+        vec![4, 5],
+        b"sender".to_vec(),
+        b"server".to_vec(),
+        b"expires_at".to_vec(),
+        b"1631020452097".to_vec(),
+      ]
+    );
+    awareness.expires_at = None;
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&awareness.to_fields_array().as_slice()),
+      vec![
+        b"data".to_vec(),
+        vec![4, 5],
+        b"sender".to_vec(),
+        b"server".to_vec(),
+      ]
+    );
+  }
+
+  #[test]
+  fn replace_update_sender() {
+    let client = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "d1".to_string(),
+    });
+    let update = CollabStreamUpdate::builder(sample_update("hello").encode_v2())
+      .sender(client.clone())
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    let forwarded = update.clone().with_sender(CollabOrigin::Server);
+    assert_eq!(forwarded.sender, CollabOrigin::Server);
+    assert_eq!(forwarded.data, update.data);
+    assert_eq!(forwarded.flags, update.flags);
+
+    let awareness = AwarenessStreamUpdate {
+      data: vec![1, 2, 3],
+      sender: client,
+      expires_at: Some(1631020452097),
+    };
+    let forwarded = awareness.with_sender(CollabOrigin::Server);
+    assert_eq!(forwarded.sender, CollabOrigin::Server);
+    assert_eq!(forwarded.data, vec![1, 2, 3]);
+    assert_eq!(forwarded.expires_at, Some(1631020452097));
+  }
+
+  #[test]
+  fn detect_snapshot_sized_update() {
+    const THRESHOLD: usize = 16 * 1024;
+
+    let delta = CollabStreamUpdate::new(sample_update("a").encode_v1(), CollabOrigin::Server, 0);
+    assert!(!delta.looks_like_snapshot(THRESHOLD));
+
+    let baseline = sample_update(&"lorem ipsum ".repeat(4096)).encode_v1();
+    let snapshot = CollabStreamUpdate::new(baseline.clone(), CollabOrigin::Server, 0);
+    assert!(snapshot.looks_like_snapshot(THRESHOLD));
+
+    // size is measured after decompression
+    let compressed = CollabStreamUpdate::builder(baseline)
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert!(compressed.data.len() < THRESHOLD);
+    assert!(compressed.looks_like_snapshot(THRESHOLD));
+
+    let corrupted = CollabStreamUpdate::new(
+      vec![1, 2, 3],
+      CollabOrigin::Server,
+      UpdateFlags::IS_COMPRESSED,
+    );
+    assert!(!corrupted.looks_like_snapshot(0));
+  }
+
+  #[test]
+  fn detect_empty_update() {
+    let update =
+      CollabStreamUpdate::new(sample_update("hello").encode_v1(), CollabOrigin::Server, 0);
+    assert!(!update.is_empty_update().unwrap());
+
+    let empty = CollabStreamUpdate::new(Update::new().encode_v1(), CollabOrigin::Server, 0);
+    assert!(empty.is_empty_update().unwrap());
+
+    let empty_v2 = CollabStreamUpdate::new(
+      Update::new().encode_v2(),
+      CollabOrigin::Server,
+      UpdateFlags::IS_V2_ENCODED,
+    );
+    assert!(empty_v2.is_empty_update().unwrap());
+
+    let garbage = CollabStreamUpdate::new(vec![0xff; 16], CollabOrigin::Server, 0);
+    assert!(garbage.is_empty_update().is_err());
+  }
+
+  #[cfg(not(feature = "redact"))]
+  #[test]
+  fn collab_stream_update_display() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "d1".to_string(),
+    });
+    let flags = UpdateFlags::from(UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED);
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], sender, flags);
+    assert_eq!(update.flags(), flags);
+    assert_eq!(
+      update.to_string(),
+      "Collab update: sender:[uid:1|device_id:d1]|flags:.v2.zstd|len:3"
+    );
+  }
+
+  #[test]
+  fn partition_is_stable_and_spread() {
+    let update = |uid: i64, device_id: &str| {
+      let sender = CollabOrigin::Client(CollabClient {
+        uid,
+        device_id: device_id.to_string(),
+      });
+      CollabStreamUpdate::new(vec![], sender, 0)
+    };
+    // fixed expectations guard against accidental change of the hash function
+    assert_eq!(update(1, "device-1").partition(16), 4);
+    assert_eq!(update(42, "desktop").partition(16), 5);
+    assert_eq!(update(1, "device-1").partition(0), 0);
+
+    let server = CollabStreamUpdate::new(vec![], CollabOrigin::Server, 0);
+    assert_eq!(server.partition(16), 0);
+    let empty = CollabStreamUpdate::new(vec![], CollabOrigin::Empty, 0);
+    assert_eq!(empty.partition(16), 0);
+
+    let mut counts = [0u32; 8];
+    for uid in 0..800 {
+      counts[update(uid, "device").partition(8) as usize] += 1;
+    }
+    for count in counts {
+      assert!(
+        (50..=150).contains(&count),
+        "uneven partitions: {:?}",
+        counts
+      );
+    }
+  }
+
+  #[test]
+  fn sender_is_remote_only_for_clients() {
+    let client = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "device".to_string(),
+    });
+    assert!(is_remote(&client));
+    assert!(!is_remote(&CollabOrigin::Server));
+    assert!(!is_remote(&CollabOrigin::Empty));
+
+    assert!(CollabStreamUpdate::new(vec![], client, 0).is_from_client());
+    assert!(!CollabStreamUpdate::new(vec![], CollabOrigin::Server, 0).is_from_client());
+    assert!(!CollabStreamUpdate::new(vec![], CollabOrigin::Empty, 0).is_from_client());
+  }
+
+  #[cfg(not(feature = "redact"))]
+  #[test]
+  fn collab_stream_update_debug_json() {
+    use base64::Engine;
+
+    let data = sample_update("hello").encode_v2();
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "d1".to_string(),
+    });
+    let update = CollabStreamUpdate::builder(data.clone())
+      .sender(sender)
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    let json = update.to_debug_json();
+    assert_eq!(json["sender"], "uid:1|device_id:d1");
+    assert_eq!(json["flags"], ".v2.zstd");
+    assert_eq!(json["data_len"], update.data.len());
+    let decoded = base64::engine::general_purpose::STANDARD
+      .decode(json["data"].as_str().unwrap())
+      .unwrap();
+    assert_eq!(decoded, data);
+    // single line, so that entries can be written as NDJSON
+    assert!(!json.to_string().contains('\n'));
+
+    let corrupted = CollabStreamUpdate::new(
+      vec![1, 2, 3],
+      CollabOrigin::Empty,
+      UpdateFlags::IS_COMPRESSED,
+    );
+    let json = corrupted.to_debug_json();
+    assert!(json["data"].is_null());
+    assert!(json["error"].is_string());
+  }
+
+  #[test]
+  fn new_validated_rejects_empty_data() {
+    let result = CollabStreamUpdate::new_validated(vec![], CollabOrigin::Server, 0);
+    assert!(matches!(result, Err(StreamError::InvalidFormat)));
+    let result = CollabStreamUpdate::new_validated(
+      vec![],
+      CollabOrigin::Server,
+      UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED,
+    );
+    assert!(matches!(result, Err(StreamError::InvalidFormat)));
+
+    // plain constructor stays permissive
+    let update = CollabStreamUpdate::new(vec![], CollabOrigin::Server, 0);
+    assert!(update.data.is_empty());
+  }
+
+  #[test]
+  fn new_validated_rejects_invalid_bytes() {
+    let result = CollabStreamUpdate::new_validated(vec![255, 255, 255], CollabOrigin::Server, 0);
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn new_validated_accepts_compressed_update() {
+    let data = zstd::encode_all(sample_update("hello").encode_v1().as_slice(), 3).unwrap();
+    let update = CollabStreamUpdate::new_validated(
+      data.clone(),
+      CollabOrigin::Server,
+      UpdateFlags::IS_COMPRESSED,
+    )
+    .unwrap();
+    assert_eq!(update.data, data);
+  }
+
+  #[test]
+  fn stream_message_into_binary_with_id() {
+    let message = StreamMessage {
+      data: Bytes::from_static(b"payload"),
+      id: MessageId::new(1631020452097, 1),
+      flags: UpdateFlags::default(),
+      sender: CollabOrigin::Empty,
+      fields: HashMap::new(),
+    };
+    let (binary, id) = message.into_binary_with_id();
+    assert_eq!(id, MessageId::new(1631020452097, 1));
+    assert_eq!(binary.as_slice(), b"payload");
+  }
+
+  #[test]
+  fn into_update_rejects_oversized_payload() {
+    let data = zstd::encode_all(vec![0u8; 1024 * 1024].as_slice(), 3).unwrap();
+    let update = CollabStreamUpdate::new(data, CollabOrigin::Server, UpdateFlags::IS_COMPRESSED);
+    let result = update.into_update_with_limit(1024);
+    assert!(matches!(result, Err(StreamError::TooLarge(1024))));
+  }
+
+  #[test]
+  fn into_update_within_limit() {
+    let data = sample_update("hello").encode_v1();
+    let limit = data.len();
+    let compressed = zstd::encode_all(data.as_slice(), 3).unwrap();
+    let update =
+      CollabStreamUpdate::new(compressed, CollabOrigin::Server, UpdateFlags::IS_COMPRESSED);
+    let decoded = update.into_update_with_limit(limit).unwrap();
+    assert_eq!(decoded, sample_update("hello"));
+  }
+
+  #[test]
+  fn message_id_range_rolls_over_sequence() {
+    let start = MessageId::new(1, u64::MAX - 1);
+    let end = MessageId::new(2, 1);
+    let ids: Vec<_> = MessageIdRange::from(start..=end).collect();
+    assert_eq!(
+      ids,
+      vec![
+        MessageId::new(1, u64::MAX - 1),
+        MessageId::new(1, u64::MAX),
+        MessageId::new(2, 0),
+        MessageId::new(2, 1),
+      ]
+    );
+  }
+
+  #[test]
+  fn message_id_range_edge_cases() {
+    let id = MessageId::new(1, 1);
+    assert_eq!(MessageIdRange::from(id..=id).collect::<Vec<_>>(), vec![id]);
+    let empty = MessageIdRange::from(MessageId::new(2, 0)..=MessageId::new(1, 0));
+    assert_eq!(empty.count(), 0);
+    let last: Vec<_> = MessageIdRange::from(MessageId::MAX..=MessageId::MAX).collect();
+    assert_eq!(last, vec![MessageId::MAX]);
+  }
+
+  #[test]
+  fn collab_stream_update_state_vector() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::new(data.clone(), CollabOrigin::Server, 0);
+    let sv = update.state_vector().unwrap();
+    assert_eq!(sv.get(&1), 5);
+
+    let compressed = CollabStreamUpdate::builder(data)
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert_eq!(compressed.state_vector().unwrap(), sv);
+  }
+
+  #[test]
+  fn stream_update_sender_accessors() {
+    let client = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let update = CollabStreamUpdate::new(vec![], client.clone(), 0);
+    assert_eq!(update.sender_device_id(), Some("test-device"));
+    assert_eq!(update.sender_uid(), Some(123));
+    let awareness = AwarenessStreamUpdate {
+      data: vec![],
+      sender: client,
+      expires_at: None,
+    };
+    assert_eq!(awareness.sender_device_id(), Some("test-device"));
+    assert_eq!(awareness.sender_uid(), Some(123));
+
+    for sender in [CollabOrigin::Server, CollabOrigin::Empty] {
+      let update = CollabStreamUpdate::new(vec![], sender.clone(), 0);
+      assert_eq!(update.sender_device_id(), None);
+      assert_eq!(update.sender_uid(), None);
+      let awareness = AwarenessStreamUpdate {
+        data: vec![],
+        sender,
+        expires_at: None,
+      };
+      assert_eq!(awareness.sender_device_id(), None);
+      assert_eq!(awareness.sender_uid(), None);
+    }
+  }
+
+  #[test]
+  fn parse_xrange_single_entry() {
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Bulk(vec![
+        Value::Data(b"data".to_vec()),
+        Value::Data(b"payload".to_vec()),
+      ]),
+    ])]);
+    let entry = SingleStreamEntry::from_redis_value(&reply)
+      .unwrap()
+      .0
+      .unwrap();
+    assert_eq!(entry.id, MessageId::new(1631020452097, 0));
+    assert_eq!(entry.data.as_ref(), b"payload");
+
+    let missing = SingleStreamEntry::from_redis_value(&Value::Bulk(vec![])).unwrap();
+    assert!(missing.0.is_none());
+  }
+
+  #[test]
+  fn collab_control_event_update_round_trip() {
+    let event = CollabControlEvent::Update {
+      object_id: "o1".to_string(),
+      encode_update: vec![1, 2, 3],
+    };
+    let encoded = event.encode().unwrap();
+    assert_eq!(CollabControlEvent::decode(&encoded).unwrap(), event);
+    #[cfg(not(feature = "redact"))]
+    assert_eq!(event.to_string(), "Update collab: object_id:o1|len:3");
+  }
+
+  #[cfg(feature = "redact")]
+  #[test]
+  fn debug_output_is_redacted() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    let update = CollabStreamUpdate::new(vec![7; 1024], sender.clone(), 0);
+    let debug = format!("{:?}", update);
+    assert!(debug.contains("test-device"), "{}", debug);
+    assert!(debug.contains("flags: .v1"), "{}", debug);
+    assert!(debug.contains("data: <redacted>"), "{}", debug);
+    assert!(!debug.contains("1024"), "{}", debug);
+    assert_eq!(
+      update.to_string(),
+      "Collab update: sender:[uid:123|device_id:test-device]|flags:.v1|data:<redacted>"
+    );
+
+    let update = AwarenessStreamUpdate {
+      data: vec![7; 1024],
+      sender,
+      expires_at: None,
+    };
+    let debug = format!("{:?}", update);
+    assert!(debug.contains("test-device"), "{}", debug);
+    assert!(debug.contains("data: <redacted>"), "{}", debug);
+    assert!(!debug.contains("1024"), "{}", debug);
+
+    let event = CollabControlEvent::Update {
+      object_id: "o1".to_string(),
+      encode_update: vec![1, 2, 3],
+    };
+    assert_eq!(
+      event.to_string(),
+      "Update collab: object_id:o1|data:<redacted>"
+    );
+    let debug = format!("{:?}", event);
+    assert!(debug.contains("encode_update: <redacted>"), "{}", debug);
+    assert!(!debug.contains("[1, 2, 3]"), "{}", debug);
+
+    let event = CollabControlEvent::Open {
+      workspace_id: "w1".to_string(),
+      object_id: "o1".to_string(),
+      collab_type: CollabType::Document,
+      doc_state: vec![7; 1024],
+    };
+    let debug = format!("{:?}", event);
+    assert!(debug.contains("object_id: \"o1\""), "{}", debug);
+    assert!(debug.contains("doc_state: <redacted>"), "{}", debug);
+    assert!(
+      !debug.contains("1024") && !debug.contains("7, 7"),
+      "{}",
+      debug
+    );
+
+    let message = StreamMessage {
+      data: Bytes::from(vec![7; 1024]),
+      id: MessageId::new(1, 2),
+      flags: UpdateFlags::default(),
+      sender: CollabOrigin::Server,
+      fields: HashMap::from([
+        ("data".to_string(), Bytes::from(vec![7; 1024])),
+        ("trace".to_string(), Bytes::from_static(b"secret-trace")),
+      ]),
+    };
+    let debug = format!("{:?}", message);
+    assert!(debug.contains("1-2"), "{}", debug);
+    assert!(debug.contains("data: <redacted>"), "{}", debug);
+    assert!(debug.contains("fields: [\"data\", \"trace\"]"), "{}", debug);
+    assert!(
+      !debug.contains("1024") && !debug.contains("secret-trace"),
+      "{}",
+      debug
+    );
+
+    let dead_letter = super::DeadLetter {
+      id: MessageId::new(1, 2),
+      key: "af:w1:o1:updates".to_string(),
+      reason: "invalid format".to_string(),
+      raw: Bytes::from(vec![7; 1024]),
+    };
+    let debug = format!("{:?}", dead_letter);
+    assert!(debug.contains("raw: <redacted>"), "{}", debug);
+    assert!(
+      !debug.contains("1024") && !debug.contains("7, 7"),
+      "{}",
+      debug
+    );
+    assert_eq!(
+      dead_letter.to_string(),
+      "dead letter 1-2 from `af:w1:o1:updates`: invalid format"
+    );
+  }
+
+  #[test]
+  fn collab_control_event_legacy_payloads() {
+    let close = br#"{"Close":{"object_id":"o1"}}"#;
+    assert_eq!(
+      CollabControlEvent::decode(close).unwrap(),
+      CollabControlEvent::Close {
+        object_id: "o1".to_string()
+      }
+    );
+
+    let open = serde_json::json!({
+      "Open": {
+        "workspace_id": "w1",
+        "object_id": "o1",
+        "collab_type": serde_json::to_value(CollabType::Document).unwrap(),
+        "doc_state": [1, 2, 3],
+      }
+    });
+    let open = serde_json::to_vec(&open).unwrap();
+    assert_eq!(
+      CollabControlEvent::decode(&open).unwrap(),
+      CollabControlEvent::Open {
+        workspace_id: "w1".to_string(),
+        object_id: "o1".to_string(),
+        collab_type: CollabType::Document,
+        doc_state: vec![1, 2, 3],
+      }
+    );
+  }
+
+  #[test]
+  fn collab_stream_update_fields_order() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    for i in 0..100u8 {
+      let update = CollabStreamUpdate::new(vec![i], sender.clone(), UpdateFlags::IS_COMPRESSED);
+      let fields = update.to_fields();
+      let names: Vec<_> = fields.iter().map(|(name, _)| *name).collect();
+      assert_eq!(names, vec!["data", "sender", "flags"]);
+      assert_eq!(fields[0].1, vec![i]);
+      assert_eq!(fields[1].1, sender.to_string().into_bytes());
+      assert_eq!(fields[2].1, b"2".to_vec());
+    }
+  }
+
+  #[test]
+  fn collab_origin_redis_string_round_trip() {
+    let origins = [
+      CollabOrigin::Empty,
+      CollabOrigin::Server,
+      CollabOrigin::Client(CollabClient {
+        uid: -42,
+        device_id: "dev|ice:uid:1|device_id:x".to_string(),
+      }),
+    ];
+    for origin in origins {
+      let encoded = collab_origin_to_redis_string(&origin);
+      assert_eq!(collab_origin_from_str(&encoded).unwrap(), origin);
+    }
+  }
+
+  #[test]
+  fn parse_collab_origin_legacy_order() {
+    let actual = collab_origin_from_str("device_id:test-device|uid:123").unwrap();
+    let expected = CollabOrigin::Client(CollabClient {
+      uid: 123,
+      device_id: "test-device".to_string(),
+    });
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn stream_message_raw_id() {
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452097-12".to_vec()),
+      Value::Bulk(vec![
+        Value::Data(b"data".to_vec()),
+        Value::Data(b"payload".to_vec()),
+      ]),
+    ]);
+    let message = StreamMessage::from_redis_value(&reply).unwrap();
+    assert_eq!(message.raw_id(), "1631020452097-12");
+  }
+
+  #[test]
+  fn stream_message_bounded_parsing() {
+    let limits = ParseLimits::default();
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Bulk(vec![
+        Value::Data(b"sender".to_vec()),
+        Value::Data(b"server".to_vec()),
+        Value::Data(b"data".to_vec()),
+        Value::Data(b"payload".to_vec()),
+      ]),
+    ]);
+    let message = StreamMessage::from_redis_value_bounded(&reply, &limits).unwrap();
+    assert_eq!(message.id, MessageId::new(1631020452097, 0));
+    assert_eq!(message.data, Bytes::from_static(b"payload"));
+  }
+
+  #[test]
+  fn stream_message_bounded_parsing_rejects_deep_nesting() {
+    let mut nested = Value::Data(b"data".to_vec());
+    for _ in 0..1000 {
+      nested = Value::Bulk(vec![nested]);
+    }
+    let reply = Value::Bulk(vec![Value::Data(b"1631020452097-0".to_vec()), nested]);
+    let result = StreamMessage::from_redis_value_bounded(&reply, &ParseLimits::default());
+    assert!(matches!(result, Err(StreamError::UnexpectedValue(_))));
+  }
+
+  #[test]
+  fn stream_message_bounded_parsing_rejects_too_many_fields() {
+    let limits = ParseLimits {
+      max_depth: 2,
+      max_fields: 4,
+    };
+    let fields = (0..5)
+      .flat_map(|i| {
+        [
+          Value::Data(format!("field{}", i).into_bytes()),
+          Value::Data(b"value".to_vec()),
+        ]
+      })
+      .collect();
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Bulk(fields),
+    ]);
+    let result = StreamMessage::from_redis_value_bounded(&reply, &limits);
+    assert!(matches!(result, Err(StreamError::UnexpectedValue(_))));
+  }
+
+  #[test]
+  fn sort_stream_messages_by_id() {
+    use rand::seq::SliceRandom;
+
+    let mut messages: Vec<_> = (0..20)
+      .map(|i| StreamMessage {
+        data: Bytes::from(vec![i as u8]),
+        id: MessageId::new(1631020452097 + i / 3, i % 3),
+        flags: UpdateFlags::default(),
+        sender: CollabOrigin::Empty,
+        fields: HashMap::new(),
+      })
+      .collect();
+    let expected: Vec<_> = messages.iter().map(|m| m.id).collect();
+    messages.shuffle(&mut rand::thread_rng());
+    sort_by_id(&mut messages);
+    let actual: Vec<_> = messages.iter().map(|m| m.id).collect();
+    assert_eq!(actual, expected);
+    assert_eq!(
+      messages[0].cmp_by_id(&messages[1]),
+      std::cmp::Ordering::Less
+    );
+  }
+
+  #[test]
+  fn merge_stream_messages_by_key() {
+    let messages = |seqs: &[u64]| -> Vec<StreamMessage> {
+      seqs
+        .iter()
+        .map(|seq| StreamMessage {
+          data: Bytes::new(),
+          id: MessageId::new(1631020452097, *seq),
+          flags: UpdateFlags::default(),
+          sender: CollabOrigin::Empty,
+          fields: HashMap::new(),
+        })
+        .collect()
+    };
+    let mut left = StreamMessageByStreamKey(BTreeMap::from([
+      ("s1".to_string(), messages(&[1, 4, 5, 9])),
+      ("s2".to_string(), messages(&[2])),
+    ]));
+    let right = StreamMessageByStreamKey(BTreeMap::from([
+      ("s1".to_string(), messages(&[2, 3, 6, 10, 11])),
+      ("s3".to_string(), messages(&[7, 8])),
+    ]));
+    left.merge(right);
+
+    let seqs = |key: &str| -> Vec<u64> {
+      left.0[key]
+        .iter()
+        .map(|message| message.id.sequence_number)
+        .collect()
+    };
+    assert_eq!(seqs("s1"), vec![1, 2, 3, 4, 5, 6, 9, 10, 11]);
+    assert_eq!(seqs("s2"), vec![2]);
+    assert_eq!(seqs("s3"), vec![7, 8]);
+    assert_eq!(left.0.len(), 3);
   }
 
   #[test]
-  fn parse_collab_origin_client() {
-    let expected = CollabOrigin::Client(CollabClient {
-      uid: 123,
-      device_id: "test-device".to_string(),
+  fn stream_messages_by_key_without_sender() {
+    let entry = |seq: u64, sender: Option<&str>| {
+      let mut fields = vec![
+        Value::Data(b"data".to_vec()),
+        Value::Data(seq.to_string().into_bytes()),
+      ];
+      if let Some(sender) = sender {
+        fields.push(Value::Data(b"sender".to_vec()));
+        fields.push(Value::Data(sender.as_bytes().to_vec()));
+      }
+      Value::Bulk(vec![
+        Value::Data(format!("1631020452097-{}", seq).into_bytes()),
+        Value::Bulk(fields),
+      ])
+    };
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"s1".to_vec()),
+      Value::Bulk(vec![
+        entry(0, Some("device_id:d1|uid:1")),
+        entry(1, Some("server")),
+        entry(2, None),
+        entry(3, Some("device_id:d2|uid:2")),
+        entry(4, Some("device_id:d1|uid:1")),
+      ]),
+    ])]);
+    let messages = StreamMessageByStreamKey::from_redis_value(&reply).unwrap();
+    let client = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "d1".to_string(),
     });
-    let actual = collab_origin_from_str(&expected.to_string()).unwrap();
-    assert_eq!(actual, expected);
+
+    let ids = |messages: &[&StreamMessage]| -> Vec<u64> {
+      messages.iter().map(|m| m.id.sequence_number).collect()
+    };
+    let filtered = messages.filter_sender(&client);
+    assert_eq!(ids(&filtered["s1"]), vec![1, 2, 3]);
+    // messages without sender are only dropped when filtering on empty origin
+    let filtered = messages.filter_sender(&CollabOrigin::Empty);
+    assert_eq!(ids(&filtered["s1"]), vec![0, 1, 3, 4]);
+
+    let filtered = messages.without_sender(&client);
+    let remaining: Vec<_> = filtered.0["s1"].iter().collect();
+    assert_eq!(ids(&remaining), vec![1, 2, 3]);
+    assert_eq!(remaining[0].sender, CollabOrigin::Server);
+    assert_eq!(remaining[1].sender, CollabOrigin::Empty);
   }
 
   #[test]
-  fn test_collab_update_event_decoding() {
-    let encoded_update = vec![1, 2, 3, 4, 5];
-    let event = super::CollabUpdateEvent::UpdateV1 {
-      encode_update: encoded_update.clone(),
+  fn read_outcome_from_reply() {
+    let outcome = ReadOutcome::from_redis_value(&Value::Nil).unwrap();
+    assert!(outcome.is_timed_out());
+    assert!(outcome.into_messages().0.is_empty());
+
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"s1".to_vec()),
+      Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(b"1631020452097-0".to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"data".to_vec()),
+          Value::Data(b"hello".to_vec()),
+        ]),
+      ])]),
+    ])]);
+    match ReadOutcome::from_redis_value(&reply).unwrap() {
+      ReadOutcome::Messages(messages) => {
+        let messages = &messages.0["s1"];
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, MessageId::new(1631020452097, 0));
+        assert_eq!(messages[0].data, b"hello".to_vec());
+      },
+      ReadOutcome::TimedOut => panic!("expected messages"),
+    }
+
+    // an empty reply is not a timeout
+    let outcome = ReadOutcome::from_redis_value(&Value::Bulk(vec![])).unwrap();
+    assert!(!outcome.is_timed_out());
+    assert!(outcome.into_messages().0.is_empty());
+  }
+
+  #[test]
+  fn stream_messages_by_key_many_entries() {
+    let entries = |count: u64| {
+      Value::Bulk(
+        (0..count)
+          .map(|i| {
+            Value::Bulk(vec![
+              Value::Data(format!("1631020452097-{}", i).into_bytes()),
+              Value::Bulk(vec![
+                Value::Data(b"data".to_vec()),
+                Value::Data(i.to_string().into_bytes()),
+              ]),
+            ])
+          })
+          .collect(),
+      )
     };
-    let encoded = event.encode();
-    let decoded = super::CollabUpdateEvent::decode(&encoded).unwrap();
-    assert_eq!(event, decoded);
+    let reply = Value::Bulk(vec![
+      Value::Bulk(vec![Value::Data(b"s1".to_vec()), entries(1000)]),
+      Value::Bulk(vec![Value::Data(b"s2".to_vec()), entries(3)]),
+    ]);
+    let map = StreamMessageByStreamKey::from_redis_value(&reply)
+      .unwrap()
+      .0;
+    let s1 = &map["s1"];
+    assert_eq!(s1.len(), 1000);
+    assert_eq!(s1.capacity(), 1000);
+    for (i, message) in s1.iter().enumerate() {
+      assert_eq!(message.id, MessageId::new(1631020452097, i as u64));
+      assert_eq!(message.data, i.to_string().into_bytes());
+    }
+    assert_eq!(map["s2"].len(), 3);
+  }
+
+  #[test]
+  fn recent_messages_into_chronological() {
+    let entry = |id: &str, data: &[u8]| {
+      Value::Bulk(vec![
+        Value::Data(id.as_bytes().to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"data".to_vec()),
+          Value::Data(data.to_vec()),
+        ]),
+      ])
+    };
+    let reply = Value::Bulk(vec![
+      entry("1631020452099-0", b"3"),
+      entry("1631020452098-0", b"2"),
+      entry("1631020452097-0", b"1"),
+    ]);
+    let recent = RecentMessages::from_redis_value(&reply).unwrap();
+    assert_eq!(recent.0[0].id, MessageId::new(1631020452099, 0));
+    let messages = recent.into_chronological();
+    let data: Vec<_> = messages.iter().map(|m| m.data.clone()).collect();
+    assert_eq!(data, vec![&b"1"[..], &b"2"[..], &b"3"[..]]);
+
+    let empty = RecentMessages::from_redis_value(&Value::Bulk(vec![])).unwrap();
+    assert!(empty.into_chronological().is_empty());
+  }
+
+  fn stream_entry(fields: Vec<(&'static str, Vec<u8>)>) -> StreamId {
+    StreamId {
+      id: "1631020452097-0".to_string(),
+      map: redis_fields(fields),
+    }
+  }
+
+  #[test]
+  fn stream_message_custom_fields() {
+    let entry = stream_entry(vec![
+      ("data", b"payload".to_vec()),
+      ("trace_id", b"4bf92f3577b34da6".to_vec()),
+      ("binary", vec![0xff, 0xfe]),
+    ]);
+    let message = StreamMessage::try_from(&entry).unwrap();
+    assert_eq!(message.field_str("trace_id"), Some("4bf92f3577b34da6"));
+    assert_eq!(message.field_bytes("data").unwrap(), &message.data);
+    assert_eq!(message.field_str("binary"), None);
+    assert_eq!(
+      message.field_bytes("binary").unwrap().as_ref(),
+      &[0xff, 0xfe]
+    );
+    assert_eq!(message.field_str("missing"), None);
+    assert_eq!(message.fields().len(), 3);
+
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Bulk(vec![
+        Value::Data(b"data".to_vec()),
+        Value::Data(b"payload".to_vec()),
+        Value::Data(b"trace_id".to_vec()),
+        Value::Data(b"4bf92f3577b34da6".to_vec()),
+      ]),
+    ]);
+    let lossy = StreamMessage::try_from_redis_value_lossy(&reply).unwrap();
+    assert_eq!(lossy.field_str("trace_id"), Some("4bf92f3577b34da6"));
+    let bounded = StreamMessage::from_redis_value_bounded(&reply, &ParseLimits::default()).unwrap();
+    assert_eq!(bounded.field_str("trace_id"), Some("4bf92f3577b34da6"));
+    assert_eq!(bounded.fields(), lossy.fields());
+  }
+
+  #[test]
+  fn stream_messages_by_key_extra_fields() {
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"s1".to_vec()),
+      Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(b"1631020452097-0".to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"trace_id".to_vec()),
+          Value::Data(b"4bf92f3577b34da6".to_vec()),
+          Value::Data(b"sender".to_vec()),
+          Value::Data(b"server".to_vec()),
+          Value::Data(b"data".to_vec()),
+          Value::Data(b"payload".to_vec()),
+          Value::Data(b"span_id".to_vec()),
+          Value::Data(b"00f067aa0ba902b7".to_vec()),
+        ]),
+      ])]),
+    ])]);
+    let messages = StreamMessageByStreamKey::from_redis_value(&reply).unwrap();
+    let message = &messages.0["s1"][0];
+    assert_eq!(message.id, MessageId::new(1631020452097, 0));
+    assert_eq!(message.data, b"payload".to_vec());
+    assert_eq!(message.sender, CollabOrigin::Server);
+    assert_eq!(message.field_str("trace_id"), Some("4bf92f3577b34da6"));
+    assert_eq!(message.field_str("span_id"), Some("00f067aa0ba902b7"));
+    assert_eq!(message.fields.len(), 4);
+  }
+
+  #[test]
+  fn stream_message_from_update_fields() {
+    let update = sample_update("hello");
+    let stream_update = CollabStreamUpdate::builder(update.encode_v2())
+      .sender(CollabOrigin::Client(CollabClient {
+        uid: 7,
+        device_id: "device".to_string(),
+      }))
+      .flags(UpdateFlags::IS_V2_ENCODED)
+      .compressed(3)
+      .build()
+      .unwrap();
+    let entry = |fields: Vec<(&'static str, Vec<u8>)>| {
+      let mut values = Vec::new();
+      for (key, value) in fields {
+        values.push(Value::Data(key.as_bytes().to_vec()));
+        values.push(Value::Data(value));
+      }
+      Value::Bulk(vec![
+        Value::Data(b"1631020452097-0".to_vec()),
+        Value::Bulk(values),
+      ])
+    };
+
+    let message =
+      StreamMessage::from_redis_value(&entry(stream_update.to_fields_with_checksum())).unwrap();
+    assert_eq!(message.flags, stream_update.flags);
+    assert_eq!(message.sender, stream_update.sender);
+    assert_eq!(message.data, stream_update.data);
+    assert_eq!(
+      message.into_yrs_update().unwrap().encode_v1(),
+      update.encode_v1()
+    );
+
+    // corrupted payload is parsed, but rejected when decoded
+    let mut corrupted = stream_update.to_fields_with_checksum();
+    corrupted[0].1 = vec![1, 2, 3];
+    let message = StreamMessage::from_redis_value(&entry(corrupted)).unwrap();
+    assert!(matches!(
+      message.verify_checksum(),
+      Err(StreamError::ChecksumMismatch { .. })
+    ));
+    assert!(matches!(
+      message.into_yrs_update(),
+      Err(StreamError::ChecksumMismatch { .. })
+    ));
+  }
+
+  #[test]
+  fn stream_messages_by_key_binary_sender() {
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 1,
+      device_id: "d1".to_string(),
+    });
+    let update = CollabStreamUpdate::new_v2(vec![1, 2, 3], sender.clone()).with_binary_sender();
+    let mut fields = Vec::new();
+    for (key, value) in update.to_fields_with_checksum() {
+      fields.push(Value::Data(key.as_bytes().to_vec()));
+      fields.push(Value::Data(value));
+    }
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"s1".to_vec()),
+      Value::Bulk(vec![Value::Bulk(vec![
+        Value::Data(b"1631020452097-0".to_vec()),
+        Value::Bulk(fields),
+      ])]),
+    ])]);
+    let messages = StreamMessageByStreamKey::from_redis_value(&reply).unwrap();
+    assert_eq!(messages.0["s1"][0].sender, sender);
+    assert!(messages.without_sender(&sender).0["s1"].is_empty());
+  }
+
+  #[test]
+  fn stream_message_into_yrs_update() {
+    let update = sample_update("hello");
+
+    let v1 = stream_entry(vec![("data", update.encode_v1())]);
+    let message = StreamMessage::try_from(&v1).unwrap();
+    assert_eq!(message.flags, UpdateFlags::default());
+    assert_eq!(
+      message.into_yrs_update().unwrap().encode_v1(),
+      update.encode_v1()
+    );
+
+    let v2 = stream_entry(vec![("data", update.encode_v2()), ("flags", b"1".to_vec())]);
+    let message = StreamMessage::try_from(&v2).unwrap();
+    assert!(message.flags.is_v2_encoded());
+    assert_eq!(
+      message.into_yrs_update().unwrap().encode_v1(),
+      update.encode_v1()
+    );
+
+    let compressed = CollabStreamUpdate::builder(update.encode_v1())
+      .compressed(3)
+      .build()
+      .unwrap();
+    let entry = stream_entry(compressed.to_fields());
+    let message = StreamMessage::try_from(&entry).unwrap();
+    assert!(message.flags.is_compressed());
+    assert_eq!(
+      message.into_yrs_update().unwrap().encode_v1(),
+      update.encode_v1()
+    );
+  }
+
+  #[test]
+  fn stream_message_lossy_missing_data() {
+    let reply = Value::Bulk(vec![
+      Value::Data(b"1631020452097-0".to_vec()),
+      Value::Bulk(vec![
+        Value::Data(b"event".to_vec()),
+        Value::Data(b"heartbeat".to_vec()),
+      ]),
+    ]);
+    assert!(StreamMessage::from_redis_value(&reply).is_err());
+    let message = StreamMessage::try_from_redis_value_lossy(&reply).unwrap();
+    assert_eq!(message.id, MessageId::new(1631020452097, 0));
+    assert!(message.data.is_empty());
+  }
+
+  #[test]
+  fn message_id_from_stream_id_without_data() {
+    let stream_id = StreamId {
+      id: "1631020452097-4".to_string(),
+      map: HashMap::new(),
+    };
+    let id = MessageId::try_from(stream_id).unwrap();
+    assert_eq!(id, MessageId::new(1631020452097, 4));
+
+    let invalid = StreamId {
+      id: "invalid".to_string(),
+      map: HashMap::new(),
+    };
+    assert!(MessageId::try_from(invalid).is_err());
+  }
+
+  #[test]
+  fn stream_message_from_borrowed_stream_id() {
+    let stream_id = StreamId {
+      id: "1631020452097-4".to_string(),
+      map: HashMap::from([("data".to_string(), Value::Data(b"payload".to_vec()))]),
+    };
+    let message = StreamMessage::try_from(&stream_id).unwrap();
+    assert_eq!(message.id, MessageId::new(1631020452097, 4));
+    assert_eq!(message.data, Bytes::from_static(b"payload"));
+
+    let missing = StreamId {
+      id: "1631020452097-5".to_string(),
+      map: HashMap::new(),
+    };
+    assert!(StreamMessage::try_from(&missing).is_err());
+  }
+
+  #[test]
+  fn chunked_update_round_trip() {
+    let update = sample_update(&"chunked content ".repeat(16));
+    let data = update.encode_v1();
+    let max_chunk = data.len().div_ceil(3) + 8;
+    let chunks = CollabStreamUpdate::chunk(
+      &data,
+      CollabOrigin::Server,
+      UpdateFlags::default(),
+      max_chunk,
+    )
+    .unwrap();
+    assert_eq!(chunks.len(), 3);
+    for chunk in &chunks {
+      assert!(chunk.data.len() <= max_chunk);
+      assert!(chunk.flags.is_chunk());
+      assert!(chunk.clone().into_update().is_err());
+    }
+    let reassembled = CollabStreamUpdate::reassemble(&chunks).unwrap();
+    assert_eq!(reassembled.encode_v1(), data);
+  }
+
+  #[test]
+  fn chunked_v2_update_round_trip() {
+    let update = sample_update(&"chunked content ".repeat(16));
+    let data = update.encode_v2();
+    let flags = UpdateFlags::from(UpdateFlags::IS_V2_ENCODED);
+    let chunks = CollabStreamUpdate::chunk(&data, CollabOrigin::Server, flags, 32).unwrap();
+    assert!(chunks.len() > 1);
+    for chunk in &chunks {
+      assert!(chunk.flags.is_chunk());
+      assert!(chunk.flags.is_v2_encoded());
+    }
+    let reassembled = CollabStreamUpdate::reassemble(&chunks).unwrap();
+    assert_eq!(reassembled.encode_v2(), data);
+  }
+
+  #[test]
+  fn chunk_rejects_too_small_max_chunk() {
+    let data = sample_update("content").encode_v1();
+    for max_chunk in [0, 1, 8] {
+      let result = CollabStreamUpdate::chunk(
+        &data,
+        CollabOrigin::Server,
+        UpdateFlags::default(),
+        max_chunk,
+      );
+      assert!(matches!(result, Err(StreamError::UnexpectedValue(_))));
+    }
+  }
+
+  #[test]
+  fn reassemble_rejects_incomplete_chunks() {
+    let data = sample_update(&"chunked content ".repeat(16)).encode_v1();
+    let mut chunks =
+      CollabStreamUpdate::chunk(&data, CollabOrigin::Server, UpdateFlags::default(), 64).unwrap();
+    assert!(CollabStreamUpdate::reassemble(&chunks[1..]).is_err());
+    chunks.swap(0, 1);
+    assert!(CollabStreamUpdate::reassemble(&chunks).is_err());
+    chunks.swap(0, 1);
+    chunks[1].flags = UpdateFlags::from(UpdateFlags::IS_CHUNK | UpdateFlags::IS_V2_ENCODED);
+    assert!(CollabStreamUpdate::reassemble(&chunks).is_err());
+    assert!(CollabStreamUpdate::reassemble(&[]).is_err());
+  }
+
+  #[test]
+  fn compression_ratio_of_compressible_update() {
+    let data = sample_update(&"a".repeat(4096)).encode_v1();
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert!(update.compressed_size() < data.len());
+    assert!(update.compression_ratio().unwrap() > 10.0);
+
+    let uncompressed = CollabStreamUpdate::builder(data).build().unwrap();
+    assert_eq!(uncompressed.compression_ratio(), None);
+  }
+
+  #[test]
+  fn compression_ratio_of_incompressible_update() {
+    // xorshift output, which zstd can't shrink
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let data: Vec<u8> = (0..4096)
+      .map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+      })
+      .collect();
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert!(update.compressed_size() >= data.len() / 2);
+    assert!(update.compression_ratio().unwrap() <= 2.0);
+  }
+
+  #[test]
+  fn stream_message_into_collab_update() {
+    let data = sample_update("hello").encode_v1();
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: data.clone(),
+    };
+    let sender = CollabOrigin::Client(CollabClient {
+      uid: 7,
+      device_id: "device".to_string(),
+    });
+    let message = StreamMessage {
+      data: Bytes::from(event.encode()),
+      id: MessageId::new(1631020452097, 0),
+      flags: UpdateFlags::default(),
+      sender: sender.clone(),
+      fields: HashMap::new(),
+    };
+    let update = message.into_collab_update_or_dead_letter("stream").unwrap();
+    assert_eq!(update.data, data);
+    assert_eq!(update.sender, sender);
+  }
+
+  #[test]
+  fn corrupted_stream_message_into_dead_letter() {
+    let raw = Bytes::from_static(&[0xF0, 0xFF, 0xFF, 0xFF]);
+    let message = StreamMessage {
+      data: raw.clone(),
+      id: MessageId::new(1631020452097, 1),
+      flags: UpdateFlags::default(),
+      sender: CollabOrigin::Empty,
+      fields: HashMap::new(),
+    };
+    let dead_letter = message
+      .into_collab_update_or_dead_letter("stream")
+      .unwrap_err();
+    assert_eq!(dead_letter.id, MessageId::new(1631020452097, 1));
+    assert_eq!(dead_letter.key, "stream");
+    assert_eq!(dead_letter.raw, raw);
+    assert!(!dead_letter.reason.is_empty());
+  }
+
+  #[test]
+  fn checksum_mismatch_into_dead_letter() {
+    let event = CollabUpdateEvent::UpdateV1 {
+      encode_update: sample_update("hello").encode_v1(),
+    };
+    let data = event.encode();
+    let entry = |id: &str, checksum: u32| {
+      Value::Bulk(vec![
+        Value::Data(id.as_bytes().to_vec()),
+        Value::Bulk(vec![
+          Value::Data(b"data".to_vec()),
+          Value::Data(data.clone()),
+          Value::Data(b"checksum".to_vec()),
+          Value::Data(checksum.to_string().into_bytes()),
+        ]),
+      ])
+    };
+    let reply = Value::Bulk(vec![Value::Bulk(vec![
+      Value::Data(b"stream".to_vec()),
+      Value::Bulk(vec![
+        entry("1631020452097-0", crc32fast::hash(&data)),
+        entry("1631020452097-1", crc32fast::hash(&data) ^ 1),
+      ]),
+    ])]);
+    // a single corrupted entry doesn't fail the whole read
+    let mut messages = StreamMessageByStreamKey::from_redis_value(&reply)
+      .unwrap()
+      .0
+      .remove("stream")
+      .unwrap();
+    assert_eq!(messages.len(), 2);
+    let corrupted = messages.pop().unwrap();
+    assert!(messages
+      .pop()
+      .unwrap()
+      .into_collab_update_or_dead_letter("stream")
+      .is_ok());
+    let dead_letter = corrupted
+      .into_collab_update_or_dead_letter("stream")
+      .unwrap_err();
+    assert_eq!(dead_letter.id, MessageId::new(1631020452097, 1));
+    assert!(
+      dead_letter.reason.contains("checksum"),
+      "{}",
+      dead_letter.reason
+    );
+  }
+
+  #[test]
+  fn default_zstd_compressor_round_trip() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed_with(Box::new(ZstdCompressor::default()))
+      .build()
+      .unwrap();
+    assert_eq!(update.flags.compression(), Compression::Zstd);
+    assert_ne!(update.data, data);
+    assert_eq!(
+      ZstdCompressor::default()
+        .decompress(&update.data, MAX_DECOMPRESSED_SIZE)
+        .unwrap(),
+      data
+    );
+    assert_eq!(update.into_update().unwrap().encode_v1(), data);
+  }
+
+  #[test]
+  fn identity_compressor_round_trip() {
+    struct IdentityCompressor;
+
+    impl Compressor for IdentityCompressor {
+      fn compression(&self) -> Compression {
+        Compression::None
+      }
+
+      fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError> {
+        Ok(data.to_vec())
+      }
+
+      fn decompress(&self, data: &[u8], _limit: usize) -> Result<Vec<u8>, StreamError> {
+        Ok(data.to_vec())
+      }
+    }
+
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed_with(Box::new(IdentityCompressor))
+      .build()
+      .unwrap();
+    assert!(!update.flags.is_compressed());
+    assert_eq!(update.data, data);
+    assert_eq!(update.into_update().unwrap().encode_v1(), data);
+  }
+
+  #[test]
+  fn custom_compressor_round_trip() {
+    /// zstd output with every byte inverted: not readable by the built-in zstd codec
+    struct InvertedZstd;
+
+    impl Compressor for InvertedZstd {
+      fn compression(&self) -> Compression {
+        Compression::Zstd
+      }
+
+      fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError> {
+        let compressed = Compression::Zstd.compress(data, 3)?;
+        Ok(compressed.into_iter().map(|b| !b).collect())
+      }
+
+      fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, StreamError> {
+        let inverted: Vec<u8> = data.iter().map(|b| !b).collect();
+        Compression::Zstd.decompress(&inverted, limit)
+      }
+    }
+
+    struct Lz4Kind;
+
+    impl Compressor for Lz4Kind {
+      fn compression(&self) -> Compression {
+        Compression::Lz4
+      }
+
+      fn compress(&self, data: &[u8]) -> Result<Vec<u8>, StreamError> {
+        Ok(data.to_vec())
+      }
+
+      fn decompress(&self, data: &[u8], _limit: usize) -> Result<Vec<u8>, StreamError> {
+        Ok(data.to_vec())
+      }
+    }
+
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed_with(Box::new(InvertedZstd))
+      .build()
+      .unwrap();
+    assert_eq!(update.flags.compression(), Compression::Zstd);
+    assert!(update.clone().into_update().is_err());
+    assert!(matches!(
+      update.clone().into_update_with(&Lz4Kind),
+      Err(StreamError::Unsupported(_))
+    ));
+    assert_eq!(
+      update.into_update_with(&InvertedZstd).unwrap().encode_v1(),
+      data
+    );
+  }
+
+  #[test]
+  fn truncated_zstd_payload() {
+    let data = sample_update(&"hello ".repeat(100)).encode_v1();
+    let compressed = Compression::Zstd.compress(&data, 3).unwrap();
+    let truncated = &compressed[..compressed.len() / 2];
+    assert!(matches!(
+      Compression::Zstd.decompress(truncated, MAX_DECOMPRESSED_SIZE),
+      Err(StreamError::Truncated)
+    ));
+    assert!(matches!(
+      Compression::Zstd.decompress(&compressed[..2], MAX_DECOMPRESSED_SIZE),
+      Err(StreamError::Truncated)
+    ));
+
+    let flags = UpdateFlags::from(UpdateFlags::IS_COMPRESSED);
+    let update = CollabStreamUpdate::new(truncated.to_vec(), CollabOrigin::Server, flags);
+    assert!(matches!(update.into_update(), Err(StreamError::Truncated)));
+  }
+
+  #[test]
+  fn non_zstd_payload_is_invalid_format() {
+    let data = sample_update("hello").encode_v1();
+    assert!(matches!(
+      Compression::Zstd.decompress(&data, MAX_DECOMPRESSED_SIZE),
+      Err(StreamError::InvalidFormat)
+    ));
+
+    let flags = UpdateFlags::from(UpdateFlags::IS_COMPRESSED);
+    let update = CollabStreamUpdate::new(data, CollabOrigin::Server, flags);
+    assert!(matches!(
+      update.into_update(),
+      Err(StreamError::InvalidFormat)
+    ));
+  }
+
+  #[test]
+  fn decode_update_from_borrowed_slice() {
+    let expected = sample_update("hello");
+
+    let v1 = expected.encode_v1();
+    assert_eq!(
+      decode_update(&v1, UpdateFlags::default()).unwrap(),
+      expected
+    );
+
+    let v2 = expected.encode_v2();
+    assert_eq!(
+      decode_update(&v2, UpdateFlags::from(UpdateFlags::IS_V2_ENCODED)).unwrap(),
+      expected
+    );
+
+    let compressed = Compression::Zstd.compress(&v2, 3).unwrap();
+    let flags = UpdateFlags::from(UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED);
+    assert_eq!(decode_update(&compressed, flags).unwrap(), expected);
+
+    assert!(decode_update(&[1, 2, 3], flags).is_err());
+  }
+
+  #[test]
+  fn zstd_compressed_update_round_trip() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::builder(data)
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert_eq!(update.flags.compression(), Compression::Zstd);
+    assert_eq!(update.flags.to_string(), ".v1.zstd");
+    assert_eq!(update.into_update().unwrap(), sample_update("hello"));
+  }
+
+  #[test]
+  fn large_zstd_compressed_update_is_decompressed_in_chunks() {
+    let content = "lorem ipsum dolor sit amet ".repeat(100_000);
+    let data = sample_update(&content).encode_v1();
+    assert!(data.len() > CHUNKED_DECOMPRESSION_THRESHOLD);
+    let update = CollabStreamUpdate::builder(data.clone())
+      .compressed(3)
+      .build()
+      .unwrap();
+    assert!(update.data.len() < CHUNKED_DECOMPRESSION_THRESHOLD);
+    assert_eq!(
+      Compression::Zstd
+        .decompress(&update.data, MAX_DECOMPRESSED_SIZE)
+        .unwrap(),
+      data
+    );
+    assert!(matches!(
+      Compression::Zstd.decompress(&update.data, data.len() - 1),
+      Err(StreamError::TooLarge(_))
+    ));
+    let mut buf = Vec::new();
+    Compression::Zstd
+      .decompress_into(&update.data, MAX_DECOMPRESSED_SIZE, &mut buf)
+      .unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(buf.capacity(), data.len());
+    assert_eq!(update.into_update().unwrap(), sample_update(&content));
+  }
+
+  #[test]
+  fn forged_zstd_content_size_is_not_allocated_upfront() {
+    let content = [7u8; 16];
+    // zstd frame with 8-byte content size field claiming 64 MiB, followed by a single raw block
+    let mut frame = ZSTD_MAGIC.to_vec();
+    frame.extend_from_slice(&[0xC0, 0x00]);
+    frame.extend_from_slice(&(64u64 * 1024 * 1024).to_le_bytes());
+    let block_header = 1 | (content.len() as u32) << 3;
+    frame.extend_from_slice(&block_header.to_le_bytes()[..3]);
+    frame.extend_from_slice(&content);
+
+    let mut buf = Vec::new();
+    assert!(Compression::Zstd
+      .decompress_into(&frame, MAX_DECOMPRESSED_SIZE, &mut buf)
+      .is_err());
+    assert!(
+      buf.capacity() <= CHUNKED_DECOMPRESSION_THRESHOLD,
+      "{}",
+      buf.capacity()
+    );
+  }
+
+  #[cfg(feature = "lz4")]
+  #[test]
+  fn lz4_compressed_update_round_trip() {
+    let data = sample_update("hello").encode_v1();
+    let update = CollabStreamUpdate::builder(data)
+      .compressed_lz4()
+      .build()
+      .unwrap();
+    assert_eq!(update.flags.compression(), Compression::Lz4);
+    assert_eq!(update.flags.to_string(), ".v1.lz4");
+    assert_eq!(update.into_update().unwrap(), sample_update("hello"));
+  }
+
+  #[cfg(not(feature = "lz4"))]
+  #[test]
+  fn lz4_compressed_update_unsupported() {
+    let flags = UpdateFlags::IS_COMPRESSED | UpdateFlags::IS_LZ4_COMPRESSED;
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, flags);
+    assert!(matches!(
+      update.into_update(),
+      Err(StreamError::Unsupported(_))
+    ));
+  }
+
+  #[test]
+  fn decode_into_reuses_scratch_buffer() {
+    let data = sample_update("hello world").encode_v1();
+    let update = CollabStreamUpdate::builder(data)
+      .compressed(3)
+      .build()
+      .unwrap();
+
+    let mut scratch = Vec::new();
+    assert_eq!(
+      update.decode_into(&mut scratch).unwrap(),
+      sample_update("hello world")
+    );
+    let capacity = scratch.capacity();
+    let ptr = scratch.as_ptr();
+    for _ in 0..10 {
+      assert_eq!(
+        update.decode_into(&mut scratch).unwrap(),
+        sample_update("hello world")
+      );
+      assert_eq!(scratch.capacity(), capacity);
+      assert_eq!(scratch.as_ptr(), ptr);
+    }
+  }
+
+  #[test]
+  fn group_create_redis_args() {
+    let args = redis::ToRedisArgs::to_redis_args(&GroupCreate::new("stream", "group"));
+    assert_eq!(
+      args,
+      vec![
+        b"CREATE".to_vec(),
+        b"stream".to_vec(),
+        b"group".to_vec(),
+        b"$".to_vec()
+      ]
+    );
+
+    let create = GroupCreate::new("stream", "group")
+      .with_start(StreamReadId::Beginning)
+      .with_mkstream();
+    let args = redis::ToRedisArgs::to_redis_args(&create);
+    assert_eq!(
+      args,
+      vec![
+        b"CREATE".to_vec(),
+        b"stream".to_vec(),
+        b"group".to_vec(),
+        b"0".to_vec(),
+        b"MKSTREAM".to_vec()
+      ]
+    );
+
+    let create =
+      GroupCreate::new("stream", "group").with_start(StreamReadId::After(MessageId::new(1, 2)));
+    let args = redis::ToRedisArgs::to_redis_args(&create);
+    assert_eq!(args[3], b"1-2".to_vec());
+  }
+
+  #[test]
+  fn multi_stream_read_redis_args() {
+    let read = MultiStreamRead::new()
+      .with_stream("s1", StreamReadId::Beginning)
+      .with_stream("s2", StreamReadId::After(MessageId::new(1, 2)))
+      .with_stream("s3", StreamReadId::NewMessages)
+      .with_count(10)
+      .with_block_ms(500);
+    let args = redis::ToRedisArgs::to_redis_args(&read);
+    assert_eq!(
+      args,
+      vec![
+        b"COUNT".to_vec(),
+        b"10".to_vec(),
+        b"BLOCK".to_vec(),
+        b"500".to_vec(),
+        b"STREAMS".to_vec(),
+        b"s1".to_vec(),
+        b"s2".to_vec(),
+        b"s3".to_vec(),
+        b"0".to_vec(),
+        b"1-2".to_vec(),
+        b"$".to_vec(),
+      ]
+    );
+
+    let read = MultiStreamRead::new().with_stream("s1", StreamReadId::Beginning);
+    let args = redis::ToRedisArgs::to_redis_args(&read);
+    assert_eq!(
+      args,
+      vec![b"STREAMS".to_vec(), b"s1".to_vec(), b"0".to_vec()]
+    );
+  }
+
+  #[test]
+  fn append_options_redis_args() {
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, 0);
+    let packed = |options: AppendOptions| {
+      let fields = if options.checksum {
+        update.to_fields_with_checksum()
+      } else {
+        update.to_fields()
+      };
+      let fields: Vec<Vec<u8>> = fields
+        .into_iter()
+        .flat_map(|(key, value)| [key.as_bytes().to_vec(), value])
+        .collect();
+      let mut expected = redis::cmd("XADD");
+      expected.arg("stream");
+      for arg in redis::ToRedisArgs::to_redis_args(&options) {
+        expected.arg(arg);
+      }
+      for field in &fields {
+        expected.arg(field.as_slice());
+      }
+      assert_eq!(
+        options.cmd("stream", &update).get_packed_command(),
+        expected.get_packed_command()
+      );
+    };
+
+    let options = AppendOptions::new().with_approx_maxlen(10000);
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&options),
+      vec![
+        b"MAXLEN".to_vec(),
+        b"~".to_vec(),
+        b"10000".to_vec(),
+        b"*".to_vec()
+      ]
+    );
+    packed(options);
+
+    let options = AppendOptions::new().with_maxlen(100).with_nomkstream();
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&options),
+      vec![
+        b"NOMKSTREAM".to_vec(),
+        b"MAXLEN".to_vec(),
+        b"=".to_vec(),
+        b"100".to_vec(),
+        b"*".to_vec()
+      ]
+    );
+    packed(options);
+
+    let options = AppendOptions::new();
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&options),
+      vec![b"*".to_vec()]
+    );
+    packed(options);
+
+    // checksum only adds an entry field
+    let options = AppendOptions::new().with_checksum();
+    assert_eq!(
+      redis::ToRedisArgs::to_redis_args(&options),
+      vec![b"*".to_vec()]
+    );
+    let cmd = options.cmd("stream", &update).get_packed_command();
+    assert!(cmd.windows(8).any(|w| w == b"checksum"));
+    packed(options);
+  }
+
+  #[test]
+  fn stream_message_age() {
+    let message = StreamMessage {
+      data: Bytes::new(),
+      id: MessageId::new(1631020452097, 0),
+      flags: UpdateFlags::default(),
+      sender: CollabOrigin::Empty,
+      fields: HashMap::new(),
+    };
+    let now = UNIX_EPOCH + Duration::from_millis(1631020453097);
+    assert_eq!(message.age(now), Duration::from_secs(1));
+
+    // message from the future due to clock skew
+    let now = UNIX_EPOCH + Duration::from_millis(1631020451097);
+    assert_eq!(message.age(now), Duration::ZERO);
+  }
+
+  #[cfg(feature = "msgpack")]
+  #[test]
+  fn collab_control_event_msgpack_round_trip() {
+    let open = CollabControlEvent::Open {
+      workspace_id: "w1".to_string(),
+      object_id: "o1".to_string(),
+      collab_type: CollabType::Document,
+      doc_state: (0..=255).collect(),
+    };
+    let encoded = open.encode_msgpack().unwrap();
+    assert_eq!(CollabControlEvent::decode_msgpack(&encoded).unwrap(), open);
+    // bytes are written as msgpack bin, not an array of integers
+    assert!(encoded.len() < open.encode().unwrap().len() / 2);
+
+    let close = CollabControlEvent::Close {
+      object_id: "o1".to_string(),
+    };
+    let encoded = close.encode_msgpack().unwrap();
+    assert_eq!(CollabControlEvent::decode_msgpack(&encoded).unwrap(), close);
+
+    // JSON encoding is unaffected by the msgpack byte encoding
+    let json = open.encode().unwrap();
+    assert_eq!(CollabControlEvent::decode(&json).unwrap(), open);
+  }
+
+  #[test]
+  fn collab_control_event_same_session() {
+    let open = |object_id: &str, doc_state: Vec<u8>| CollabControlEvent::Open {
+      workspace_id: "w1".to_string(),
+      object_id: object_id.to_string(),
+      collab_type: CollabType::Document,
+      doc_state,
+    };
+    assert!(open("o1", vec![1, 2, 3]).same_session(&open("o1", vec![4, 5])));
+    assert!(!open("o1", vec![1, 2, 3]).same_session(&open("o2", vec![1, 2, 3])));
+
+    let close = |object_id: &str| CollabControlEvent::Close {
+      object_id: object_id.to_string(),
+    };
+    assert!(close("o1").same_session(&close("o1")));
+    assert!(!close("o1").same_session(&close("o2")));
+    assert!(!close("o1").same_session(&open("o1", vec![])));
+  }
+
+  #[test]
+  fn collab_control_event_open_from_doc_state() {
+    let doc = Doc::with_client_id(1);
+    let text = doc.get_or_insert_text("text");
+    text.insert(&mut doc.transact_mut(), 0, "hello");
+    let state = doc
+      .transact()
+      .encode_state_as_update_v1(&StateVector::default());
+
+    let open = CollabControlEvent::open_from_doc_state("w1", "o1", CollabType::Document, &state);
+    let decoded = CollabControlEvent::decode(&open.encode().unwrap()).unwrap();
+    assert_eq!(decoded, open);
+
+    let doc_state = match decoded {
+      CollabControlEvent::Open {
+        workspace_id,
+        object_id,
+        collab_type,
+        doc_state,
+      } => {
+        assert_eq!(workspace_id, "w1");
+        assert_eq!(object_id, "o1");
+        assert_eq!(collab_type, CollabType::Document);
+        doc_state
+      },
+      other => panic!("expected Open event, got {}", other),
+    };
+    let restored = Doc::with_client_id(2);
+    let text = restored.get_or_insert_text("text");
+    restored
+      .transact_mut()
+      .apply_update(Update::decode_v1(&doc_state).unwrap())
+      .unwrap();
+    assert_eq!(text.get_string(&restored.transact()), "hello");
+  }
+
+  #[test]
+  fn collab_control_event_accessors() {
+    let open = CollabControlEvent::Open {
+      workspace_id: "w1".to_string(),
+      object_id: "o1".to_string(),
+      collab_type: CollabType::Database,
+      doc_state: vec![1, 2, 3],
+    };
+    assert_eq!(open.object_id(), "o1");
+    assert_eq!(open.collab_type(), Some(CollabType::Database));
+
+    let close = CollabControlEvent::Close {
+      object_id: "o2".to_string(),
+    };
+    assert_eq!(close.object_id(), "o2");
+    assert_eq!(close.collab_type(), None);
+
+    let update = CollabControlEvent::Update {
+      object_id: "o3".to_string(),
+      encode_update: vec![1, 2, 3],
+    };
+    assert_eq!(update.object_id(), "o3");
+    assert_eq!(update.collab_type(), None);
+  }
+
+  #[test]
+  fn collab_control_event_encoded_len() {
+    let close = CollabControlEvent::Close {
+      object_id: "o1".to_string(),
+    };
+    assert_eq!(close.encoded_len().unwrap(), close.encode().unwrap().len());
+
+    let open = CollabControlEvent::Open {
+      workspace_id: "w1".to_string(),
+      object_id: "o1".to_string(),
+      collab_type: CollabType::Document,
+      doc_state: vec![255; 1024],
+    };
+    let len = open.encoded_len().unwrap();
+    assert_eq!(len, open.encode().unwrap().len());
+    assert!(len > close.encoded_len().unwrap() + 1024);
+  }
+
+  fn redis_fields(fields: Vec<(&'static str, Vec<u8>)>) -> HashMap<String, Value> {
+    fields
+      .into_iter()
+      .map(|(name, value)| (name.to_string(), Value::Data(value)))
+      .collect()
+  }
+
+  #[test]
+  fn checksum_matches() {
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, 0);
+    let fields = redis_fields(update.to_fields_with_checksum());
+    let parsed = CollabStreamUpdate::try_from(fields).unwrap();
+    assert_eq!(parsed, update);
+  }
+
+  #[test]
+  fn checksum_detects_tampered_data() {
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, 0);
+    let mut fields = redis_fields(update.to_fields_with_checksum());
+    fields.insert("data".to_string(), Value::Data(vec![1, 2]));
+    let result = CollabStreamUpdate::try_from(fields);
+    assert!(matches!(result, Err(StreamError::ChecksumMismatch { .. })));
+  }
+
+  #[test]
+  fn missing_checksum_is_accepted() {
+    let update = CollabStreamUpdate::new(vec![1, 2, 3], CollabOrigin::Server, 0);
+    let fields = redis_fields(update.to_fields());
+    let parsed = CollabStreamUpdate::try_from(fields).unwrap();
+    assert_eq!(parsed, update);
   }
 }
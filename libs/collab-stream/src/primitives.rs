@@ -0,0 +1,664 @@
+//! Plain value types shared by Redis stream entries. They don't depend on `redis`, `zstd` or
+//! `collab`, so they're available even with `default-features = false`:
+//!
+//! ```
+//! use collab_stream::primitives::{Compression, MessageId, UpdateFlags};
+//!
+//! let id = MessageId::try_from("1631020452097-3").unwrap();
+//! assert_eq!(id, MessageId::new(1631020452097, 3));
+//!
+//! let flags = UpdateFlags::from(UpdateFlags::IS_V2_ENCODED | UpdateFlags::IS_COMPRESSED);
+//! assert_eq!(flags.compression(), Compression::Zstd);
+//! assert_eq!(flags.to_string(), ".v2.zstd");
+//! ```
+
+use crate::error::StreamError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The [MessageId] generated by XADD has two parts: a timestamp and a sequence number, separated by
+/// a hyphen (-). The timestamp is based on the server's time when the message is added, and the
+/// sequence number is used to differentiate messages added at the same millisecond.
+///
+///  If multiple messages are added within the same millisecond, Redis increments the sequence number
+/// for each subsequent message
+///
+/// An example message ID might look like this: 1631020452097-0. In this example, 1631020452097 is
+/// the timestamp in milliseconds, and 0 is the sequence number.
+///
+/// Both parts are 64-bit in Redis. Previously `sequence_number` was a `u16`, which made ids of
+/// very hot streams (more than 65535 entries within the same millisecond) fail to parse. Code
+/// constructing [MessageId] from `u16` values should convert them with `u64::from`.
+#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq)]
+pub struct MessageId {
+  pub timestamp_ms: u64,
+  pub sequence_number: u64,
+}
+
+impl MessageId {
+  /// The smallest possible message id: `0-0`.
+  pub const MIN: MessageId = MessageId {
+    timestamp_ms: 0,
+    sequence_number: 0,
+  };
+
+  /// The greatest possible message id.
+  pub const MAX: MessageId = MessageId {
+    timestamp_ms: u64::MAX,
+    sequence_number: u64::MAX,
+  };
+
+  pub fn new(timestamp_ms: u64, sequence_number: u64) -> Self {
+    MessageId {
+      timestamp_ms,
+      sequence_number,
+    }
+  }
+
+  /// Returns the server time at which the message was added to the stream.
+  pub fn timestamp(&self) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(self.timestamp_ms)
+  }
+
+  /// Returns approximate time lag of a consumer which processed messages up to this id, behind
+  /// the stream `head` (ie. its `last-generated-id`). If this id is ahead of the `head`, zero is
+  /// returned.
+  pub fn lag_since(&self, head: MessageId) -> Duration {
+    head
+      .timestamp()
+      .duration_since(self.timestamp())
+      .unwrap_or(Duration::ZERO)
+  }
+
+  /// Returns the smallest message id greater than the current one: the sequence number is
+  /// incremented, rolling over to the next millisecond on overflow. Returns `None` for
+  /// [MessageId::MAX].
+  pub fn next(&self) -> Option<MessageId> {
+    match self.sequence_number.checked_add(1) {
+      Some(sequence_number) => Some(MessageId::new(self.timestamp_ms, sequence_number)),
+      None => Some(MessageId::new(self.timestamp_ms.checked_add(1)?, 0)),
+    }
+  }
+
+  /// Returns `true` if this message comes strictly after `cursor`. This mirrors exclusive ranges
+  /// used by `XREAD STREAMS key <cursor>`: the message at `cursor` itself is considered already
+  /// processed and won't be delivered again.
+  pub fn is_after(&self, cursor: MessageId) -> bool {
+    *self > cursor
+  }
+
+  /// Returns `true` if this message is the `cursor` message itself or comes after it. This
+  /// mirrors inclusive ranges used by `XRANGE key <cursor> +`, where the boundary message is
+  /// delivered as well.
+  pub fn is_at_or_after(&self, cursor: MessageId) -> bool {
+    *self >= cursor
+  }
+
+  /// Returns a message id marking the cutoff point for entries older than `retention`, relative
+  /// to `now`. It can be passed directly to `XTRIM key MINID <id>`, which will evict all entries
+  /// with ids lower than returned one.
+  ///
+  /// If `retention` reaches before UNIX epoch, [MessageId::MIN] is returned.
+  pub fn minid_before(now: SystemTime, retention: Duration) -> MessageId {
+    let cutoff = now
+      .checked_sub(retention)
+      .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok());
+    match cutoff {
+      Some(cutoff) => MessageId::new(cutoff.as_millis() as u64, 0),
+      None => MessageId::MIN,
+    }
+  }
+}
+
+/// Lazy iterator over every possible [MessageId] within an inclusive range, created from
+/// `start..=end`. Since the id space is enormous, callers should keep the range bounded.
+#[derive(Debug, Clone)]
+pub struct MessageIdRange {
+  next: Option<MessageId>,
+  end: MessageId,
+}
+
+impl From<RangeInclusive<MessageId>> for MessageIdRange {
+  fn from(range: RangeInclusive<MessageId>) -> Self {
+    let (start, end) = range.into_inner();
+    MessageIdRange {
+      next: Some(start).filter(|start| *start <= end),
+      end,
+    }
+  }
+}
+
+impl Iterator for MessageIdRange {
+  type Item = MessageId;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let current = self.next?;
+    self.next = current.next().filter(|next| *next <= self.end);
+    Some(current)
+  }
+}
+
+impl Display for MessageId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}-{}", self.timestamp_ms, self.sequence_number)
+  }
+}
+
+impl MessageId {
+  /// Appends the id in its `{timestamp}-{sequence}` form to `buf`, without allocating a new
+  /// string.
+  pub fn write_to(&self, buf: &mut String) {
+    use std::fmt::Write;
+    write!(buf, "{}", self).expect("writing into String never fails");
+  }
+
+  /// Formats the id into a fixed-size, stack allocated buffer.
+  pub fn to_array_string(&self) -> MessageIdStr {
+    use std::fmt::Write;
+    let mut s = MessageIdStr {
+      buf: [0; MessageIdStr::MAX_LEN],
+      len: 0,
+    };
+    write!(s, "{}", self).expect("MessageIdStr fits any message id");
+    s
+  }
+}
+
+/// [MessageId] formatted into a fixed-size buffer, see [MessageId::to_array_string].
+#[derive(Clone, Copy)]
+pub struct MessageIdStr {
+  buf: [u8; MessageIdStr::MAX_LEN],
+  len: usize,
+}
+
+impl MessageIdStr {
+  /// Length of the longest id: two `u64::MAX` numbers separated by `-`.
+  pub const MAX_LEN: usize = 20 + 1 + 20;
+
+  pub fn as_str(&self) -> &str {
+    // only complete UTF-8 strings are ever written into the buffer
+    std::str::from_utf8(&self.buf[..self.len]).expect("MessageIdStr is valid UTF-8")
+  }
+}
+
+impl std::fmt::Write for MessageIdStr {
+  fn write_str(&mut self, s: &str) -> std::fmt::Result {
+    let end = self.len + s.len();
+    if end > Self::MAX_LEN {
+      return Err(std::fmt::Error);
+    }
+    self.buf[self.len..end].copy_from_slice(s.as_bytes());
+    self.len = end;
+    Ok(())
+  }
+}
+
+impl std::ops::Deref for MessageIdStr {
+  type Target = str;
+
+  fn deref(&self) -> &Self::Target {
+    self.as_str()
+  }
+}
+
+impl AsRef<str> for MessageIdStr {
+  fn as_ref(&self) -> &str {
+    self.as_str()
+  }
+}
+
+impl Display for MessageIdStr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl std::fmt::Debug for MessageIdStr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Debug::fmt(self.as_str(), f)
+  }
+}
+
+impl TryFrom<&[u8]> for MessageId {
+  type Error = StreamError;
+
+  fn try_from(s: &[u8]) -> Result<Self, Self::Error> {
+    let s = std::str::from_utf8(s)?;
+    Self::try_from(s)
+  }
+}
+
+impl TryFrom<&str> for MessageId {
+  type Error = StreamError;
+
+  fn try_from(s: &str) -> Result<Self, Self::Error> {
+    let parts: Vec<_> = s.splitn(2, '-').collect();
+
+    if parts.len() != 2 {
+      return Err(StreamError::InvalidFormat);
+    }
+
+    // Directly parse without intermediate assignment.
+    let timestamp_ms = u64::from_str(parts[0])?;
+    let sequence_number = u64::from_str(parts[1])?;
+
+    Ok(MessageId {
+      timestamp_ms,
+      sequence_number,
+    })
+  }
+}
+
+impl TryFrom<String> for MessageId {
+  type Error = StreamError;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    Self::try_from(s.as_str())
+  }
+}
+
+/// Per-key consumer cursors, persisted so that consumers can resume reading after restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CursorSnapshot(pub BTreeMap<String, MessageId>);
+
+impl CursorSnapshot {
+  /// Length of a serialized [MessageId]: timestamp and sequence number as u64 little endian.
+  const ID_LEN: usize = 16;
+
+  /// Serializes cursors into a compact binary format: for every key, its byte length (u32 little
+  /// endian) followed by the key itself and [MessageId::timestamp_ms] and
+  /// [MessageId::sequence_number] (both u64 little endian).
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let len = self.0.keys().map(|key| 4 + key.len() + Self::ID_LEN).sum();
+    let mut buf = Vec::with_capacity(len);
+    for (key, id) in &self.0 {
+      buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+      buf.extend_from_slice(key.as_bytes());
+      buf.extend_from_slice(&id.timestamp_ms.to_le_bytes());
+      buf.extend_from_slice(&id.sequence_number.to_le_bytes());
+    }
+    buf
+  }
+
+  /// Deserializes cursors written by [CursorSnapshot::to_bytes]. Returns
+  /// [StreamError::Truncated] if `data` ends in the middle of an entry.
+  pub fn from_bytes(mut data: &[u8]) -> Result<Self, StreamError> {
+    let mut cursors = BTreeMap::new();
+    while !data.is_empty() {
+      let key_len = u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap()) as usize;
+      let key = std::str::from_utf8(take(&mut data, key_len)?)?.to_string();
+      let id = take(&mut data, Self::ID_LEN)?;
+      let id = MessageId::new(
+        u64::from_le_bytes(id[..8].try_into().unwrap()),
+        u64::from_le_bytes(id[8..].try_into().unwrap()),
+      );
+      cursors.insert(key, id);
+    }
+    Ok(CursorSnapshot(cursors))
+  }
+}
+
+/// Splits first `len` bytes off `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], StreamError> {
+  if data.len() < len {
+    return Err(StreamError::Truncated);
+  }
+  let (head, tail) = data.split_at(len);
+  *data = tail;
+  Ok(head)
+}
+
+/// Encoding of yrs update payload. It's recorded in [UpdateFlags], so that readers know which
+/// decoder to use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+  V1,
+  V2,
+}
+
+/// Compression algorithm used for update payload. It's recorded in [UpdateFlags], so that readers
+/// know which decompressor to use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+  None,
+  Zstd,
+  /// Decompresses much faster than zstd at a cost of compression ratio. Compressing and
+  /// decompressing requires `lz4` feature.
+  Lz4,
+}
+
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UpdateFlags(u8);
+
+impl UpdateFlags {
+  /// Flag bit to mark if update is encoded using [EncoderV2] (if set) or [EncoderV1] (if clear).
+  pub const IS_V2_ENCODED: u8 = 0b0000_0001;
+  /// Flag bit to mark if update is compressed.
+  pub const IS_COMPRESSED: u8 = 0b0000_0010;
+  /// Flag bit to mark if compressed update uses lz4 (if set) or zstd (if clear). It's only
+  /// meaningful together with [UpdateFlags::IS_COMPRESSED].
+  pub const IS_LZ4_COMPRESSED: u8 = 0b0000_0100;
+  /// Flag bit to mark if update data is only a single framed chunk of a larger update.
+  pub const IS_CHUNK: u8 = 0b0000_1000;
+  /// Flag bit to mark if the `sender` field is stored in compact binary form (see
+  /// `encode_origin`) rather than the string form.
+  pub const IS_BINARY_SENDER: u8 = 0b0001_0000;
+
+  #[inline]
+  pub fn is_v2_encoded(&self) -> bool {
+    self.0 & Self::IS_V2_ENCODED != 0
+  }
+
+  #[inline]
+  pub fn is_v1_encoded(&self) -> bool {
+    !self.is_v2_encoded()
+  }
+
+  #[inline]
+  pub fn is_compressed(&self) -> bool {
+    self.0 & Self::IS_COMPRESSED != 0
+  }
+
+  #[inline]
+  pub fn is_chunk(&self) -> bool {
+    self.0 & Self::IS_CHUNK != 0
+  }
+
+  #[inline]
+  pub fn has_binary_sender(&self) -> bool {
+    self.0 & Self::IS_BINARY_SENDER != 0
+  }
+
+  /// Returns encoding used by the update.
+  pub fn encoding(&self) -> Encoding {
+    if self.is_v2_encoded() {
+      Encoding::V2
+    } else {
+      Encoding::V1
+    }
+  }
+
+  /// Returns compression algorithm used by the update.
+  pub fn compression(&self) -> Compression {
+    if !self.is_compressed() {
+      Compression::None
+    } else if self.0 & Self::IS_LZ4_COMPRESSED != 0 {
+      Compression::Lz4
+    } else {
+      Compression::Zstd
+    }
+  }
+
+  #[cfg(feature = "redis")]
+  pub(crate) fn set_encoding(&mut self, encoding: Encoding) {
+    match encoding {
+      Encoding::V1 => self.0 &= !Self::IS_V2_ENCODED,
+      Encoding::V2 => self.0 |= Self::IS_V2_ENCODED,
+    }
+  }
+
+  #[cfg(feature = "redis")]
+  pub(crate) fn set_binary_sender(&mut self, binary: bool) {
+    if binary {
+      self.0 |= Self::IS_BINARY_SENDER;
+    } else {
+      self.0 &= !Self::IS_BINARY_SENDER;
+    }
+  }
+
+  #[cfg(feature = "redis")]
+  pub(crate) fn set_chunk(&mut self, chunk: bool) {
+    if chunk {
+      self.0 |= Self::IS_CHUNK;
+    } else {
+      self.0 &= !Self::IS_CHUNK;
+    }
+  }
+
+  pub(crate) fn set_compression(&mut self, compression: Compression) {
+    self.0 &= !(Self::IS_COMPRESSED | Self::IS_LZ4_COMPRESSED);
+    match compression {
+      Compression::None => {},
+      Compression::Zstd => self.0 |= Self::IS_COMPRESSED,
+      Compression::Lz4 => self.0 |= Self::IS_COMPRESSED | Self::IS_LZ4_COMPRESSED,
+    }
+  }
+}
+
+impl From<u8> for UpdateFlags {
+  #[inline]
+  fn from(value: u8) -> Self {
+    UpdateFlags(value)
+  }
+}
+
+impl From<UpdateFlags> for u8 {
+  #[inline]
+  fn from(value: UpdateFlags) -> Self {
+    value.0
+  }
+}
+
+impl Display for UpdateFlags {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.encoding() {
+      Encoding::V1 => write!(f, ".v1")?,
+      Encoding::V2 => write!(f, ".v2")?,
+    }
+
+    match self.compression() {
+      Compression::None => {},
+      Compression::Zstd => write!(f, ".zstd")?,
+      Compression::Lz4 => write!(f, ".lz4")?,
+    }
+
+    if self.is_chunk() {
+      write!(f, ".chunk")?;
+    }
+
+    if self.has_binary_sender() {
+      write!(f, ".bsender")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Parses the [Display] form of [UpdateFlags], ie. `.v2.zstd.chunk.bsender`.
+impl FromStr for UpdateFlags {
+  type Err = StreamError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut flags = UpdateFlags::default();
+    let rest = if let Some(rest) = s.strip_prefix(".v1") {
+      rest
+    } else if let Some(rest) = s.strip_prefix(".v2") {
+      flags.0 |= Self::IS_V2_ENCODED;
+      rest
+    } else {
+      return Err(StreamError::InvalidFormat);
+    };
+    let rest = if let Some(rest) = rest.strip_prefix(".zstd") {
+      flags.set_compression(Compression::Zstd);
+      rest
+    } else if let Some(rest) = rest.strip_prefix(".lz4") {
+      flags.set_compression(Compression::Lz4);
+      rest
+    } else {
+      rest
+    };
+    let rest = match rest.strip_prefix(".chunk") {
+      Some(rest) => {
+        flags.0 |= Self::IS_CHUNK;
+        rest
+      },
+      None => rest,
+    };
+    match rest {
+      "" => {},
+      ".bsender" => flags.0 |= Self::IS_BINARY_SENDER,
+      _ => return Err(StreamError::InvalidFormat),
+    }
+    Ok(flags)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::error::StreamError;
+  use crate::primitives::{CursorSnapshot, MessageId, MessageIdStr, UpdateFlags};
+  use proptest::prelude::*;
+  use std::collections::BTreeMap;
+  use std::time::Duration;
+
+  #[test]
+  fn message_id_relative_to_cursor() {
+    let cursor = MessageId::new(1631020452097, 1);
+
+    assert!(!cursor.is_after(cursor));
+    assert!(cursor.is_at_or_after(cursor));
+
+    let before = MessageId::new(1631020452097, 0);
+    assert!(!before.is_after(cursor));
+    assert!(!before.is_at_or_after(cursor));
+
+    let after = MessageId::new(1631020452097, 2);
+    assert!(after.is_after(cursor));
+    assert!(after.is_at_or_after(cursor));
+
+    let next_ms = MessageId::new(1631020452098, 0);
+    assert!(next_ms.is_after(cursor));
+  }
+
+  #[test]
+  fn cursor_snapshot_round_trip() {
+    let snapshot = CursorSnapshot(BTreeMap::from([
+      (
+        "af:w1:o1:updates".to_string(),
+        MessageId::new(1631020452097, 3),
+      ),
+      ("af:w1:o2:updates".to_string(), MessageId::MIN),
+      ("af:w1:o3:awareness".to_string(), MessageId::MAX),
+      ("".to_string(), MessageId::new(1, 70000)),
+    ]));
+    let bytes = snapshot.to_bytes();
+    let expected_len: usize = snapshot.0.keys().map(|key| 4 + key.len() + 16).sum();
+    assert_eq!(bytes.len(), expected_len);
+    assert_eq!(CursorSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+    assert_eq!(
+      CursorSnapshot::from_bytes(&[]).unwrap(),
+      CursorSnapshot::default()
+    );
+    for len in [1, 5, bytes.len() - 1] {
+      assert!(matches!(
+        CursorSnapshot::from_bytes(&bytes[..len]),
+        Err(StreamError::Truncated)
+      ));
+    }
+  }
+
+  #[test]
+  fn message_id_write_to_buffer() {
+    for id in [
+      MessageId::MIN,
+      MessageId::new(1631020452097, 3),
+      MessageId::MAX,
+    ] {
+      let mut buf = String::from("af:stream:");
+      id.write_to(&mut buf);
+      assert_eq!(buf, format!("af:stream:{}", id));
+
+      let s = id.to_array_string();
+      assert_eq!(s.as_str(), id.to_string());
+      assert_eq!(&*s, id.to_string());
+    }
+    assert_eq!(
+      MessageId::MAX.to_array_string().len(),
+      MessageIdStr::MAX_LEN
+    );
+  }
+
+  #[test]
+  fn message_id_lag_since_head() {
+    let head = MessageId::new(1631020462097, 5);
+
+    let behind = MessageId::new(1631020452097, 9);
+    assert_eq!(behind.lag_since(head), Duration::from_secs(10));
+
+    assert_eq!(head.lag_since(head), Duration::ZERO);
+    let same_ms = MessageId::new(1631020462097, 0);
+    assert_eq!(same_ms.lag_since(head), Duration::ZERO);
+
+    // clock skew between consumer and Redis server
+    let ahead = MessageId::new(1631020472097, 0);
+    assert_eq!(ahead.lag_since(head), Duration::ZERO);
+  }
+
+  #[test]
+  fn parse_message_id_with_large_sequence_number() {
+    let id = MessageId::try_from("1631020452097-70000").unwrap();
+    assert_eq!(id, MessageId::new(1631020452097, 70000));
+    assert_eq!(id.to_string(), "1631020452097-70000");
+
+    let id = MessageId::try_from(format!("0-{}", u64::MAX)).unwrap();
+    assert_eq!(id.sequence_number, u64::MAX);
+  }
+
+  #[test]
+  fn parse_update_flags() {
+    let flags: UpdateFlags = ".v2.lz4.chunk".parse().unwrap();
+    assert!(flags.is_v2_encoded());
+    assert!(flags.is_chunk());
+    assert_eq!(flags.to_string(), ".v2.lz4.chunk");
+
+    let flags: UpdateFlags = ".v1.zstd.bsender".parse().unwrap();
+    assert!(flags.has_binary_sender());
+    assert!(!flags.is_chunk());
+    assert_eq!(flags.to_string(), ".v1.zstd.bsender");
+
+    for invalid in [
+      "",
+      ".v3",
+      "v1",
+      ".v1.gzip",
+      ".v1.chunk.zstd",
+      ".v1.zstd.lz4",
+      ".v1.bsender.chunk",
+      ".v1.chunk.chunk",
+    ] {
+      assert!(invalid.parse::<UpdateFlags>().is_err(), "{}", invalid);
+    }
+  }
+
+  /// Flag bits that can be represented in [UpdateFlags] display form: lz4 bit is only meaningful
+  /// together with compression bit.
+  fn known_flags() -> impl Strategy<Value = u8> {
+    (0u8..32).prop_filter("lz4 bit requires compression bit", |bits| {
+      bits & UpdateFlags::IS_LZ4_COMPRESSED == 0 || bits & UpdateFlags::IS_COMPRESSED != 0
+    })
+  }
+
+  proptest! {
+    #[test]
+    fn message_id_display_round_trip(
+      timestamp_ms in any::<u64>(),
+      sequence_number in any::<u64>(),
+    ) {
+      let id = MessageId::new(timestamp_ms, sequence_number);
+      prop_assert_eq!(MessageId::try_from(id.to_string().as_str()).unwrap(), id);
+    }
+
+    #[test]
+    fn update_flags_display_round_trip(bits in known_flags()) {
+      let flags = UpdateFlags::from(bits);
+      prop_assert_eq!(flags.to_string().parse::<UpdateFlags>().unwrap(), flags);
+    }
+  }
+}
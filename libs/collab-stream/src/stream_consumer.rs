@@ -0,0 +1,107 @@
+use crate::error::StreamError;
+use crate::model::{MessageId, ReadOutcome, StreamMessage};
+use redis::aio::ConnectionManager;
+use redis::streams::StreamReadOptions;
+use redis::AsyncCommands;
+
+/// Reads messages from a single Redis stream using `XREAD BLOCK ... STREAMS key id`, keeping track
+/// of the last read message id, so that every call to [StreamConsumer::next_batch] continues where
+/// the previous one ended and no message is read twice.
+pub struct StreamConsumer {
+  connection_manager: ConnectionManager,
+  stream_key: String,
+  cursor: MessageId,
+  block_millis: usize,
+  count: Option<usize>,
+}
+
+impl StreamConsumer {
+  /// Default time for which [StreamConsumer::next_batch] blocks waiting for new messages.
+  pub const DEFAULT_BLOCK_MILLIS: usize = 5000;
+
+  /// Creates a new consumer reading messages with ids greater than `since`. Use [MessageId::MIN]
+  /// to read the stream from the beginning.
+  pub fn new(connection_manager: ConnectionManager, stream_key: String, since: MessageId) -> Self {
+    StreamConsumer {
+      connection_manager,
+      stream_key,
+      cursor: since,
+      block_millis: Self::DEFAULT_BLOCK_MILLIS,
+      count: None,
+    }
+  }
+
+  /// Sets how long [StreamConsumer::next_batch] waits for new messages before giving up.
+  pub fn with_block_millis(mut self, block_millis: usize) -> Self {
+    self.block_millis = block_millis;
+    self
+  }
+
+  /// Sets the max number of messages returned by a single [StreamConsumer::next_batch] call.
+  pub fn with_count(mut self, count: usize) -> Self {
+    self.count = Some(count);
+    self
+  }
+
+  pub fn stream_key(&self) -> &str {
+    &self.stream_key
+  }
+
+  /// Returns id of the last message read by this consumer.
+  pub fn cursor(&self) -> MessageId {
+    self.cursor
+  }
+
+  /// Waits for messages newer than the current cursor and returns them, advancing the cursor to
+  /// the last returned message. If no message arrived before the block timeout, an empty vec is
+  /// returned and the cursor stays unchanged.
+  pub async fn next_batch(&mut self) -> Result<Vec<StreamMessage>, StreamError> {
+    self.read(self.count).await
+  }
+
+  /// Same as [StreamConsumer::next_batch], but returns at most `count` messages (`XREAD COUNT`),
+  /// so that a slow processor is never handed more than it can handle. If fewer than `count`
+  /// messages are available, they are returned right away instead of waiting for more.
+  pub async fn next_batch_with_count(
+    &mut self,
+    count: usize,
+  ) -> Result<Vec<StreamMessage>, StreamError> {
+    // `COUNT 0` is interpreted by Redis as no limit at all
+    self.read(Some(count.max(1))).await
+  }
+
+  /// Same as [StreamConsumer::next_batch], but tells apart the block timeout
+  /// ([ReadOutcome::TimedOut]) from a reply with no messages, ie. to drive heartbeats. The cursor
+  /// is advanced the same way.
+  pub async fn next_outcome(&mut self) -> Result<ReadOutcome, StreamError> {
+    self.read_outcome(self.count).await
+  }
+
+  async fn read(&mut self, count: Option<usize>) -> Result<Vec<StreamMessage>, StreamError> {
+    let messages = self
+      .read_outcome(count)
+      .await?
+      .into_messages()
+      .0
+      .remove(&self.stream_key)
+      .unwrap_or_default();
+    Ok(messages)
+  }
+
+  async fn read_outcome(&mut self, count: Option<usize>) -> Result<ReadOutcome, StreamError> {
+    let mut options = StreamReadOptions::default().block(self.block_millis);
+    if let Some(count) = count {
+      options = options.count(count);
+    }
+    let outcome: ReadOutcome = self
+      .connection_manager
+      .xread_options(&[&self.stream_key], &[self.cursor], &options)
+      .await?;
+    if let ReadOutcome::Messages(messages) = &outcome {
+      if let Some(last) = messages.0.get(&self.stream_key).and_then(|m| m.last()) {
+        self.cursor = last.id;
+      }
+    }
+    Ok(outcome)
+  }
+}
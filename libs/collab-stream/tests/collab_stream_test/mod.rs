@@ -1,3 +1,4 @@
+mod stream_consumer_test;
 mod stream_group_test;
 mod stream_test;
 mod test_util;
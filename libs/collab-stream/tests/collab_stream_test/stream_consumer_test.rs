@@ -0,0 +1,82 @@
+use crate::collab_stream_test::test_util::{random_i64, redis_client};
+use collab::core::origin::CollabOrigin;
+use collab_stream::model::{CollabStreamUpdate, MessageId, UpdateFlags};
+use collab_stream::stream_consumer::StreamConsumer;
+use redis::AsyncCommands;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn stream_consumer_advances_cursor_test() {
+  let stream_key = format!("af:consumer_test:{}", random_i64());
+  let mut conn = redis_client().await.get_connection_manager().await.unwrap();
+  let mut consumer =
+    StreamConsumer::new(conn.clone(), stream_key.clone(), MessageId::MIN).with_block_millis(100);
+
+  // nothing was written yet: reply times out and cursor stays in place
+  assert!(consumer.next_batch().await.unwrap().is_empty());
+  assert_eq!(consumer.cursor(), MessageId::MIN);
+
+  // entries are written the same way as by CollabUpdateSink
+  for i in 0..3u8 {
+    let update = CollabStreamUpdate::new_v2(vec![i], CollabOrigin::Server);
+    let _: String = conn
+      .xadd(&stream_key, "*", &update.to_fields_with_checksum())
+      .await
+      .unwrap();
+  }
+  let messages = consumer.next_batch().await.unwrap();
+  assert_eq!(messages.len(), 3);
+  assert_eq!(consumer.cursor(), messages[2].id);
+  for (i, message) in messages.iter().enumerate() {
+    assert_eq!(message.data, vec![i as u8]);
+    assert_eq!(message.sender, CollabOrigin::Server);
+    assert_eq!(message.flags, UpdateFlags::from(UpdateFlags::IS_V2_ENCODED));
+  }
+
+  // already read messages are not returned again
+  assert!(consumer.next_batch().await.unwrap().is_empty());
+
+  let _: String = conn
+    .xadd(&stream_key, "*", &[("data", vec![3u8])])
+    .await
+    .unwrap();
+  let messages = consumer.next_batch().await.unwrap();
+  assert_eq!(messages.len(), 1);
+  assert_eq!(messages[0].data, vec![3u8]);
+
+  let _: () = conn.del(&stream_key).await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_consumer_batch_never_exceeds_count_test() {
+  let stream_key = format!("af:consumer_test:{}", random_i64());
+  let mut conn = redis_client().await.get_connection_manager().await.unwrap();
+  for i in 0..5u8 {
+    let update = CollabStreamUpdate::new(vec![i], CollabOrigin::Empty, UpdateFlags::default());
+    let _: String = conn
+      .xadd(&stream_key, "*", &update.to_fields_with_checksum())
+      .await
+      .unwrap();
+  }
+
+  let mut consumer =
+    StreamConsumer::new(conn.clone(), stream_key.clone(), MessageId::MIN).with_block_millis(5000);
+  let mut received = Vec::new();
+  for expected_len in [2, 2] {
+    let messages = consumer.next_batch_with_count(2).await.unwrap();
+    assert_eq!(messages.len(), expected_len);
+    received.extend(messages);
+  }
+
+  // only one message is left: it should be returned promptly instead of waiting for a full batch
+  let start = Instant::now();
+  let messages = consumer.next_batch_with_count(2).await.unwrap();
+  assert!(start.elapsed() < Duration::from_secs(1));
+  assert_eq!(messages.len(), 1);
+  received.extend(messages);
+
+  let data: Vec<_> = received.iter().map(|m| m.data[0]).collect();
+  assert_eq!(data, vec![0, 1, 2, 3, 4]);
+
+  let _: () = conn.del(&stream_key).await.unwrap();
+}
@@ -945,6 +945,7 @@ impl CollabPersister {
     let update = AwarenessStreamUpdate {
       data: awareness_update,
       sender: sender_session.clone(),
+      expires_at: None,
     };
     let msg_id = self.awareness_sink.send(&update).await?;
     tracing::trace!(